@@ -48,7 +48,7 @@ use protocol::JsonPacketStream;
 
 use devtools_traits::{ChromeToDevtoolsControlMsg, ConsoleMessage, DevtoolsControlMsg};
 use devtools_traits::{DevtoolsPageInfo, DevtoolScriptControlMsg, LogLevel, NetworkEvent};
-use devtools_traits::{ScriptToDevtoolsControlMsg};
+use devtools_traits::{ScriptToDevtoolsControlMsg, WebSocketFrameDirection};
 use msg::constellation_msg::{PipelineId, WorkerId};
 use util::task::spawn_named;
 
@@ -110,6 +110,22 @@ struct NetworkEventUpdateMsg {
     response: ResponseStartMsg,
 }
 
+#[derive(RustcEncodable)]
+struct WebSocketFrameMsg {
+    from: String,
+    __type__: String,
+    opCode: u8,
+    payloadLength: usize,
+    payload: String,
+}
+
+#[derive(RustcEncodable)]
+struct WebSocketClosedMsg {
+    from: String,
+    __type__: String,
+    code: Option<u16>,
+}
+
 /// Spin up a devtools server that listens for connections on the specified port.
 pub fn start_server(port: u16) -> Sender<DevtoolsControlMsg> {
     let (sender, receiver) = channel();
@@ -347,6 +363,31 @@ fn run_server(sender: Sender<DevtoolsControlMsg>,
                     stream.write_json_packet(&msg);
                 }
             }
+            NetworkEvent::WebSocketFrame(frame) => {
+                let msg = WebSocketFrameMsg {
+                    from: netevent_actor_name,
+                    __type__: match frame.direction {
+                        WebSocketFrameDirection::Sent => "frameSent".to_owned(),
+                        WebSocketFrameDirection::Received => "frameReceived".to_owned(),
+                    },
+                    opCode: frame.opcode,
+                    payloadLength: frame.length,
+                    payload: frame.preview,
+                };
+                for stream in connections.iter_mut() {
+                    stream.write_json_packet(&msg);
+                }
+            }
+            NetworkEvent::WebSocketClosed(code) => {
+                let msg = WebSocketClosedMsg {
+                    from: netevent_actor_name,
+                    __type__: "webSocketClosed".to_owned(),
+                    code: code,
+                };
+                for stream in connections.iter_mut() {
+                    stream.write_json_packet(&msg);
+                }
+            }
             //TODO: Send the other types of update messages at appropriate times
             //      requestHeaders, requestCookies, responseHeaders, securityInfo, etc
         }
@@ -236,7 +236,28 @@ pub enum CachedConsoleMessage {
 #[derive(Clone)]
 pub enum NetworkEvent {
     HttpRequest(Url, Method, Headers, Option<Vec<u8>>),
-    HttpResponse(Option<Headers>, Option<RawStatus>, Option<Vec<u8>>)
+    HttpResponse(Option<Headers>, Option<RawStatus>, Option<Vec<u8>>),
+    /// A frame was sent or received on a WebSocket connection.
+    WebSocketFrame(WebSocketFrame),
+    /// A WebSocket connection closed, with the status code of the close
+    /// frame that ended it, if any.
+    WebSocketClosed(Option<u16>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum WebSocketFrameDirection {
+    Sent,
+    Received,
+}
+
+#[derive(Clone)]
+pub struct WebSocketFrame {
+    pub direction: WebSocketFrameDirection,
+    pub opcode: u8,
+    /// The length of the whole payload, in bytes.
+    pub length: usize,
+    /// The beginning of the payload, decoded as UTF-8.
+    pub preview: String,
 }
 
 impl TimelineMarker {
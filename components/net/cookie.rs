@@ -155,7 +155,7 @@ impl Cookie {
             }
         }
 
-        if self.cookie.secure && url.scheme != "https".to_string() {
+        if self.cookie.secure && url.scheme != "https" && url.scheme != "wss" {
             return false;
         }
         if self.cookie.httponly && source == CookieSource::NonHTTP {
@@ -8,6 +8,7 @@
 #![feature(mpsc_select)]
 #![feature(path_ext)]
 #![feature(plugin)]
+#![feature(socket_timeout)]
 #![feature(vec_push_all)]
 
 #![plugin(regex_macros)]
@@ -21,13 +22,17 @@ extern crate hyper;
 extern crate ipc_channel;
 extern crate png;
 #[macro_use]
+extern crate profile_traits;
+#[macro_use]
 extern crate log;
 extern crate openssl;
+extern crate rand;
 extern crate rustc_serialize;
 extern crate util;
 extern crate time;
 extern crate url;
 extern crate uuid;
+extern crate websocket;
 
 extern crate regex;
 
@@ -44,6 +49,7 @@ pub mod resource_task;
 pub mod hsts;
 pub mod storage_task;
 pub mod mime_classifier;
+pub mod websocket_loader;
 
 /// An implementation of the [Fetch spec](https://fetch.spec.whatwg.org/)
 pub mod fetch {
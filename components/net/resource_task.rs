@@ -8,6 +8,7 @@ use about_loader;
 use data_loader;
 use file_loader;
 use http_loader;
+use websocket_loader::{self, PendingConnections};
 use cookie_storage::CookieStorage;
 use cookie;
 use mime_classifier::MIMEClassifier;
@@ -15,6 +16,7 @@ use mime_classifier::MIMEClassifier;
 use net_traits::{ControlMsg, LoadData, LoadResponse, LoadConsumer};
 use net_traits::{Metadata, ProgressMsg, ResourceTask, AsyncResponseTarget, ResponseAction, CookieSource};
 use net_traits::ProgressMsg::Done;
+use net_traits::{WebSocketCommunicate, WebSocketConnectData};
 use util::opts;
 use util::task::spawn_named;
 use url::Url;
@@ -229,6 +231,9 @@ impl ResourceChannelManager {
               ControlMsg::Load(load_data, consumer) => {
                   self.resource_manager.load(load_data, consumer)
               }
+              ControlMsg::WebsocketConnect(connect, connect_data) => {
+                  self.resource_manager.websocket_connect(connect, connect_data)
+              }
               ControlMsg::SetCookiesForUrl(request, cookie_list, source) => {
                   self.resource_manager.set_cookies_for_url(request, cookie_list, source)
               }
@@ -255,7 +260,8 @@ pub struct ResourceManager {
     resource_task: Sender<ControlMsg>,
     mime_classifier: Arc<MIMEClassifier>,
     devtools_chan: Option<Sender<DevtoolsControlMsg>>,
-    hsts_list: Arc<Mutex<HSTSList>>
+    hsts_list: Arc<Mutex<HSTSList>>,
+    pending_websockets: Arc<PendingConnections>,
 }
 
 impl ResourceManager {
@@ -269,7 +275,8 @@ impl ResourceManager {
             resource_task: resource_task,
             mime_classifier: Arc::new(MIMEClassifier::new()),
             devtools_chan: devtools_channel,
-            hsts_list: Arc::new(Mutex::new(hsts_list))
+            hsts_list: Arc::new(Mutex::new(hsts_list)),
+            pending_websockets: Arc::new(PendingConnections::new(opts::get().websocket_connections_per_host)),
         }
     }
 }
@@ -287,6 +294,14 @@ impl ResourceManager {
         }
     }
 
+    fn websocket_connect(&mut self, connect: WebSocketCommunicate, connect_data: WebSocketConnectData) {
+        // Secure cookies are only sent over wss: connections.
+        let cookies = self.cookie_storage.cookies_for_url(&connect_data.resource_url, CookieSource::HTTP);
+        websocket_loader::init(connect, connect_data, cookies, self.user_agent.clone(),
+                               self.resource_task.clone(), self.pending_websockets.clone(),
+                               self.devtools_chan.clone());
+    }
+
     pub fn add_hsts_entry(&mut self, entry: HSTSEntry) {
         self.hsts_list.lock().unwrap().push(entry);
     }
@@ -0,0 +1,1838 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The network side of WebSocket connections.
+
+use devtools_traits::{ChromeToDevtoolsControlMsg, DevtoolsControlMsg, NetworkEvent};
+use devtools_traits::{WebSocketFrame, WebSocketFrameDirection};
+use flate2::{Compress, Compression, Decompress, Flush, Status};
+use hyper::header::{Headers, Host, UserAgent};
+use hyper::http::RawStatus;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use ipc_channel::ipc;
+use ipc_channel::router::ROUTER;
+use net_traits::{ControlMsg, CookieSource, MessageData, ResourceTask};
+use net_traits::{WebSocketCommunicate, WebSocketConnectData, WebSocketDomAction, WebSocketNetworkEvent};
+use openssl::nid::Nid;
+use openssl::ssl::{SslContext, SslMethod, SslStream, SSL_VERIFY_NONE, SSL_VERIFY_PEER};
+use openssl::ssl::error::SslError;
+use profile_traits::mem::{self, Report, ReportKind, Reporter, ReporterRequest};
+use rand::{OsRng, Rng};
+use std::ascii::AsciiExt;
+use std::borrow::{Cow, ToOwned};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::mem as std_mem;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Select, Sender, TryRecvError};
+use std::thread::sleep_ms;
+use std::time::Duration;
+use time;
+use url::Url;
+use util::opts;
+use util::resource_files::resources_dir_path;
+use util::task::spawn_named;
+use uuid;
+use websocket::Message;
+use websocket::client::receiver::Receiver;
+use websocket::client::request::Request;
+use websocket::client::response::Response;
+use websocket::client::sender::Sender as WebSocketSender;
+use websocket::dataframe::{DataFrame, Opcode};
+use websocket::header::{Origin, WebSocketExtensions, WebSocketProtocol};
+use websocket::header::extensions::Extension;
+use websocket::message::CloseData;
+use websocket::result::{WebSocketError, WebSocketResult};
+use websocket::stream::WebSocketStream;
+use websocket::ws::util::url::parse_url;
+
+/// Commands for the thread owning the sending half of a connection.
+enum WebSocketCommand {
+    /// Write a message to the connection; the byte count is reported back in
+    /// a `MessageSent` event once it has been written.
+    SendMessage(Message, u32),
+    /// Like `SendMessage`, for binary data shared with the script task, which
+    /// is written from there a chunk at a time when it is large.
+    SendBlob(Arc<Vec<u8>>, u32),
+    /// Answer a ping frame with a pong carrying the same application data.
+    Pong(Vec<u8>),
+    /// Send a keepalive ping.
+    Ping,
+    /// Send a close frame.
+    Close(Option<CloseData>),
+    /// Drop the connection without a closing handshake.
+    Abort,
+    /// The receive loop ended, after the close frame of the server or the
+    /// loss of the connection, so nothing more can be exchanged once any
+    /// close frame queued before this is written.
+    ReceiveEnded,
+}
+
+/// How long a server may take to answer the opening handshake.
+const HANDSHAKE_TIMEOUT_SECS: u64 = 30;
+
+/// How many times a connection is retried when the host name couldn't be
+/// resolved.
+const CONNECT_RETRIES: u32 = 1;
+
+/// How long resolving the host name of a connection may take.
+const RESOLVE_TIMEOUT_MS: u32 = 10_000;
+
+/// How long to wait before retrying a connection.
+const CONNECT_RETRY_DELAY_MS: u32 = 500;
+
+/// How long the writer gets to write what it was given before an abort,
+/// such as the close frame of a document going away, before the connection
+/// is shut down from under it.
+const ABORT_GRACE_PERIOD_MS: u32 = 500;
+
+/// After how many seconds without any frame being sent or received the
+/// connection thread pings the server, to keep NATs and proxies from dropping
+/// an idle connection. Zero disables keepalive pings.
+const KEEPALIVE_INTERVAL_SECS: u32 = 30;
+
+/// The largest message, in bytes, the connection accepts once its fragments
+/// have been reassembled.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// The bytes a connection holds on to, as reported to the memory profiler.
+#[derive(Clone)]
+pub struct MemoryUsage {
+    /// The size of the messages waiting to be written.
+    pub send_queue: Arc<AtomicUsize>,
+    /// The size of the fragmented message being reassembled.
+    pub reassembly_buffer: Arc<AtomicUsize>,
+}
+
+impl MemoryUsage {
+    pub fn new() -> MemoryUsage {
+        MemoryUsage {
+            send_queue: Arc::new(AtomicUsize::new(0)),
+            reassembly_buffer: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// The memory reports for the connection to `url`.
+    pub fn reports(&self, url: &Url) -> Vec<Report> {
+        let path_seg = format!("url({})", url.serialize());
+        vec![
+            Report {
+                path: path!["websocket", path_seg, "send-queue"],
+                kind: ReportKind::ExplicitJemallocHeapSize,
+                size: self.send_queue.load(Ordering::SeqCst),
+            },
+            Report {
+                path: path!["websocket", path_seg, "reassembly-buffer"],
+                kind: ReportKind::ExplicitJemallocHeapSize,
+                size: self.reassembly_buffer.load(Ordering::SeqCst),
+            },
+        ]
+    }
+}
+
+/// How many frames, and how many payload bytes, a connection has sent and
+/// received. Not exposed to the web; for tuning and tests.
+pub struct FrameCounters {
+    pub frames_sent: AtomicUsize,
+    pub bytes_sent: AtomicUsize,
+    pub frames_received: AtomicUsize,
+    pub bytes_received: AtomicUsize,
+}
+
+impl FrameCounters {
+    pub fn new() -> FrameCounters {
+        FrameCounters {
+            frames_sent: AtomicUsize::new(0),
+            bytes_sent: AtomicUsize::new(0),
+            frames_received: AtomicUsize::new(0),
+            bytes_received: AtomicUsize::new(0),
+        }
+    }
+
+    fn count_sent(&self, payload_length: usize) {
+        self.frames_sent.fetch_add(1, Ordering::SeqCst);
+        self.bytes_sent.fetch_add(payload_length, Ordering::SeqCst);
+    }
+
+    fn count_received(&self, payload_length: usize) {
+        self.frames_received.fetch_add(1, Ordering::SeqCst);
+        self.bytes_received.fetch_add(payload_length, Ordering::SeqCst);
+    }
+}
+
+/// How many bytes of a frame's payload are shown to devtools.
+const DEVTOOLS_PREVIEW_LENGTH: usize = 256;
+
+/// Reports `event` about the connection with the given devtools request id,
+/// if devtools are enabled.
+fn send_devtools_event(devtools_chan: &Option<Sender<DevtoolsControlMsg>>, request_id: &str,
+                       event: NetworkEvent) {
+    if let Some(ref chan) = *devtools_chan {
+        let _ = chan.send(DevtoolsControlMsg::FromChrome(
+            ChromeToDevtoolsControlMsg::NetworkEventMessage(request_id.to_owned(), event)));
+    }
+}
+
+/// Reports a frame with the given opcode and payload to devtools, if they
+/// are enabled.
+fn send_devtools_frame(devtools_chan: &Option<Sender<DevtoolsControlMsg>>, request_id: &str,
+                       direction: WebSocketFrameDirection, opcode: u8, payload: &[u8]) {
+    if devtools_chan.is_none() {
+        return;
+    }
+    let preview = &payload[..min(payload.len(), DEVTOOLS_PREVIEW_LENGTH)];
+    let frame = WebSocketFrame {
+        direction: direction,
+        opcode: opcode,
+        length: payload.len(),
+        preview: String::from_utf8_lossy(preview).into_owned(),
+    };
+    send_devtools_event(devtools_chan, request_id, NetworkEvent::WebSocketFrame(frame));
+}
+
+/// The opcode and payload of the frame `message` is sent as. Only the
+/// payload of a close frame needs building; the others are borrowed.
+fn message_payload(message: &Message) -> (Opcode, Cow<[u8]>) {
+    match *message {
+        Message::Text(ref text) => (Opcode::Text, Cow::Borrowed(text.as_bytes())),
+        Message::Binary(ref data) => (Opcode::Binary, Cow::Borrowed(&data[..])),
+        Message::Close(Some(ref data)) => {
+            let mut payload = vec![(data.status_code >> 8) as u8, data.status_code as u8];
+            payload.push_all(data.reason.as_bytes());
+            (Opcode::Close, Cow::Owned(payload))
+        }
+        Message::Close(None) => (Opcode::Close, Cow::Borrowed(&[][..])),
+        Message::Ping(ref data) => (Opcode::Ping, Cow::Borrowed(&data[..])),
+        Message::Pong(ref data) => (Opcode::Pong, Cow::Borrowed(&data[..])),
+    }
+}
+
+/// Reports a message written to the connection to devtools, if they are
+/// enabled.
+fn send_devtools_message(devtools_chan: &Option<Sender<DevtoolsControlMsg>>, request_id: &str,
+                         message: &Message) {
+    if devtools_chan.is_none() {
+        return;
+    }
+    let (opcode, payload) = message_payload(message);
+    send_devtools_frame(devtools_chan, request_id, WebSocketFrameDirection::Sent, opcode as u8, &payload);
+}
+
+/// Writes `payload` as a single, final frame with the given opcode, masked
+/// with `masking_key`. `compressed` sets the RSV1 bit of permessage-deflate.
+// https://tools.ietf.org/html/rfc6455#section-5.2
+pub fn write_frame<W: Write>(writer: &mut W, opcode: u8, compressed: bool, payload: &[u8],
+                             masking_key: [u8; 4]) -> io::Result<()> {
+    let mut frame = frame_header(0x80 | if compressed { 0x40 } else { 0 } | opcode, payload.len() as u64,
+                                 masking_key);
+    frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ masking_key[i % 4]));
+    writer.write_all(&frame)
+}
+
+/// The header of a masked frame with the given first byte and payload
+/// length, up to and including the masking key.
+fn frame_header(first_byte: u8, length: u64, masking_key: [u8; 4]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(14);
+    header.push(first_byte);
+    if length < 126 {
+        header.push(0x80 | length as u8);
+    } else if length <= 0xffff {
+        header.push(0x80 | 126);
+        header.push((length >> 8) as u8);
+        header.push(length as u8);
+    } else {
+        header.push(0x80 | 127);
+        for shift in (0..8).rev() {
+            header.push((length >> (8 * shift)) as u8);
+        }
+    }
+    header.push_all(&masking_key);
+    header
+}
+
+/// How many bytes of a large payload are masked and written at a time. A
+/// multiple of 4, so that every chunk starts with the first byte of the
+/// masking key.
+pub const WRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Like `write_frame` for an uncompressed payload, which is masked and
+/// written a chunk at a time, so that only one chunk of it is ever copied.
+pub fn write_frame_in_chunks<W: Write>(writer: &mut W, opcode: u8, payload: &[u8],
+                                       masking_key: [u8; 4]) -> io::Result<()> {
+    try!(writer.write_all(&frame_header(0x80 | opcode, payload.len() as u64, masking_key)));
+    let mut masked = Vec::with_capacity(min(payload.len(), WRITE_CHUNK_SIZE));
+    for chunk in payload.chunks(WRITE_CHUNK_SIZE) {
+        masked.clear();
+        masked.extend(chunk.iter().enumerate().map(|(i, byte)| byte ^ masking_key[i % 4]));
+        try!(writer.write_all(&masked));
+    }
+    Ok(())
+}
+
+/// Writes messages to a connection as client frames, each masked with a
+/// fresh key from the operating system's random number generator, so that
+/// the keys can't be predicted.
+// https://tools.ietf.org/html/rfc6455#section-10.3
+pub struct FrameWriter {
+    rng: OsRng,
+    /// Compresses data messages if permessage-deflate is in use.
+    deflater: Option<Deflater>,
+    counters: Arc<FrameCounters>,
+}
+
+impl FrameWriter {
+    pub fn new(deflater: Option<Deflater>) -> io::Result<FrameWriter> {
+        Ok(FrameWriter {
+            rng: try!(OsRng::new()),
+            deflater: deflater,
+            counters: Arc::new(FrameCounters::new()),
+        })
+    }
+
+    /// The counters of the frames written so far, which the receiving side of
+    /// the connection shares.
+    pub fn counters(&self) -> Arc<FrameCounters> {
+        self.counters.clone()
+    }
+
+    pub fn write_message<W: Write>(&mut self, writer: &mut W, message: &Message) -> io::Result<()> {
+        let (opcode, payload) = message_payload(message);
+        self.write_payload(writer, opcode, &payload)
+    }
+
+    /// Writes a frame with the given opcode and payload, compressing the
+    /// payload of a data frame if permessage-deflate is in use.
+    pub fn write_payload<W: Write>(&mut self, writer: &mut W, opcode: Opcode, payload: &[u8]) -> io::Result<()> {
+        let compressed = match (opcode, self.deflater.as_mut()) {
+            (Opcode::Text, Some(deflater)) | (Opcode::Binary, Some(deflater)) => Some(deflater.deflate(payload)),
+            _ => None,
+        };
+        let payload = compressed.as_ref().map_or(payload, |compressed| &compressed[..]);
+        try!(write_frame(writer, opcode as u8, compressed.is_some(), payload, self.rng.gen()));
+        self.counters.count_sent(payload.len());
+        Ok(())
+    }
+
+    /// Whether binary messages can be written with `write_binary_in_chunks`;
+    /// compressing a message makes a copy of all of it anyway.
+    pub fn can_write_in_chunks(&self) -> bool {
+        self.deflater.is_none()
+    }
+
+    /// Writes a binary message, a chunk at a time.
+    pub fn write_binary_in_chunks<W: Write>(&mut self, writer: &mut W, data: &[u8]) -> io::Result<()> {
+        try!(write_frame_in_chunks(writer, Opcode::Binary as u8, data, self.rng.gen()));
+        self.counters.count_sent(data.len());
+        Ok(())
+    }
+}
+
+/// How many bytes of frames are collected before they are written, when
+/// more commands are queued.
+pub const WRITE_BATCH_LIMIT: usize = 16 * 1024;
+
+/// Frames waiting to be written to the connection together, so that a burst
+/// of small messages takes few writes on the socket. Messages are still each
+/// their own frame.
+pub struct WriteBatch {
+    buffer: Vec<u8>,
+    /// The byte lengths of the data messages in `buffer`.
+    messages: Vec<u32>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch {
+            buffer: vec![],
+            messages: vec![],
+        }
+    }
+
+    /// Adds the frame of `message`, which is a data message of
+    /// `data_byte_len` bytes if given.
+    pub fn push(&mut self, frame_writer: &mut FrameWriter, message: &Message, data_byte_len: Option<u32>) {
+        // Writing to memory can't fail.
+        let _ = frame_writer.write_message(&mut self.buffer, message);
+        if let Some(data_byte_len) = data_byte_len {
+            self.messages.push(data_byte_len);
+        }
+    }
+
+    /// Adds the frame of a binary message of `data_byte_len` bytes, whose
+    /// payload is `data`, without copying it into a message first.
+    pub fn push_binary(&mut self, frame_writer: &mut FrameWriter, data: &[u8], data_byte_len: u32) {
+        // Writing to memory can't fail.
+        let _ = frame_writer.write_payload(&mut self.buffer, Opcode::Binary, data);
+        self.messages.push(data_byte_len);
+    }
+
+    /// Whether the batch should be written before adding to it.
+    pub fn is_full(&self) -> bool {
+        self.buffer.len() >= WRITE_BATCH_LIMIT
+    }
+
+    /// Writes the batch to `writer` in one go, and empties it. Returns the
+    /// byte lengths of the data messages that were written.
+    pub fn flush<W: Write>(&mut self, writer: &mut W) -> io::Result<Vec<u32>> {
+        let messages = std_mem::replace(&mut self.messages, vec![]);
+        if !self.buffer.is_empty() {
+            let written = writer.write_all(&self.buffer);
+            self.buffer.clear();
+            try!(written);
+        }
+        Ok(messages)
+    }
+}
+
+/// The name of the permessage-deflate extension.
+// https://tools.ietf.org/html/rfc7692
+pub const PERMESSAGE_DEFLATE: &'static str = "permessage-deflate";
+
+/// The empty stored block that ends the output of a sync flush, which is
+/// left out of compressed messages.
+// https://tools.ietf.org/html/rfc7692#section-7.2.1
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// The permessage-deflate parameters agreed to by the server.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DeflateParams {
+    /// Whether each message we send is compressed on its own.
+    pub client_no_context_takeover: bool,
+    /// Whether each message the server sends is compressed on its own.
+    pub server_no_context_takeover: bool,
+}
+
+/// Checks the extensions the server agreed to, returning the negotiated
+/// permessage-deflate parameters if that extension is in use. Fails if the
+/// server selected an extension that wasn't offered, or accepted
+/// permessage-deflate with parameters that weren't offered or aren't valid.
+// https://tools.ietf.org/html/rfc7692#section-7.1
+pub fn negotiate_deflate(extensions: &[Extension]) -> Result<Option<DeflateParams>, ()> {
+    // https://tools.ietf.org/html/rfc6455#section-4.1
+    // Client handshake requirements, step 5
+    if extensions.iter().any(|extension| extension.name != PERMESSAGE_DEFLATE) {
+        return Err(());
+    }
+    let mut responses = extensions.iter();
+    let response = match responses.next() {
+        Some(response) => response,
+        None => return Ok(None),
+    };
+    if responses.next().is_some() {
+        return Err(());
+    }
+    let mut params = DeflateParams::default();
+    let mut window_bits_seen = false;
+    for param in &response.params {
+        match (&*param.name, param.value.as_ref()) {
+            ("client_no_context_takeover", None) if !params.client_no_context_takeover => {
+                params.client_no_context_takeover = true;
+            }
+            ("server_no_context_takeover", None) if !params.server_no_context_takeover => {
+                params.server_no_context_takeover = true;
+            }
+            // The decompressor's window is always large enough.
+            ("server_max_window_bits", Some(bits)) if !window_bits_seen => {
+                match bits.parse::<u8>() {
+                    Ok(bits) if bits >= 8 && bits <= 15 => window_bits_seen = true,
+                    _ => return Err(()),
+                }
+            }
+            _ => return Err(()),
+        }
+    }
+    Ok(Some(params))
+}
+
+/// Compresses the data messages sent on a connection using
+/// permessage-deflate.
+pub struct Deflater {
+    compress: Compress,
+    /// Whether to start each message with an empty sliding window.
+    no_context_takeover: bool,
+}
+
+impl Deflater {
+    pub fn new(no_context_takeover: bool) -> Deflater {
+        Deflater {
+            compress: Compress::new(Compression::Default, false),
+            no_context_takeover: no_context_takeover,
+        }
+    }
+
+    /// Compresses the payload of a message.
+    // https://tools.ietf.org/html/rfc7692#section-7.2.1
+    pub fn deflate(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(data.len() + 64);
+        let total_in = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - total_in) as usize;
+            self.compress.compress_vec(&data[consumed..], &mut output, Flush::Sync);
+            // The flush is complete once zlib doesn't fill the output.
+            if output.len() < output.capacity() {
+                break;
+            }
+            output.reserve(data.len() + 64);
+        }
+        if output.ends_with(&DEFLATE_TRAILER) {
+            let length = output.len() - DEFLATE_TRAILER.len();
+            output.truncate(length);
+        }
+        // https://tools.ietf.org/html/rfc7692#section-7.1.1.2
+        if self.no_context_takeover {
+            self.compress = Compress::new(Compression::Default, false);
+        }
+        output
+    }
+}
+
+/// Decompresses the data messages received on a connection using
+/// permessage-deflate.
+pub struct Inflater {
+    decompress: Decompress,
+    /// Whether each message starts with an empty sliding window.
+    no_context_takeover: bool,
+}
+
+impl Inflater {
+    pub fn new(no_context_takeover: bool) -> Inflater {
+        Inflater {
+            decompress: Decompress::new(false),
+            no_context_takeover: no_context_takeover,
+        }
+    }
+
+    /// Decompresses the payload of a message, failing with the status code
+    /// to close the connection with if the data is invalid or decompresses
+    /// to more than `max_size` bytes.
+    // https://tools.ietf.org/html/rfc7692#section-7.2.2
+    pub fn inflate(&mut self, data: &[u8], max_size: usize) -> Result<Vec<u8>, u16> {
+        let mut input = data.to_vec();
+        input.push_all(&DEFLATE_TRAILER);
+        let mut output: Vec<u8> = Vec::with_capacity(min(data.len() * 4 + 64, max_size + 1));
+        let total_in = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - total_in) as usize;
+            let produced = output.len();
+            let status = self.decompress.decompress_vec(&input[consumed..], &mut output, Flush::Sync);
+            if output.len() > max_size {
+                return Err(MESSAGE_TOO_BIG);
+            }
+            match status {
+                // A final block ends the compressed stream, so the next
+                // message starts a new one.
+                Ok(Status::StreamEnd) => {
+                    self.decompress = Decompress::new(false);
+                    return Ok(output);
+                }
+                Ok(_) => {}
+                Err(_) => return Err(1007),
+            }
+            let new_consumed = (self.decompress.total_in() - total_in) as usize;
+            if output.len() < output.capacity() {
+                if new_consumed == input.len() {
+                    // https://tools.ietf.org/html/rfc7692#section-7.1.1.1
+                    if self.no_context_takeover {
+                        self.decompress = Decompress::new(false);
+                    }
+                    return Ok(output);
+                }
+                if new_consumed == consumed && output.len() == produced {
+                    // The data is truncated.
+                    return Err(1007);
+                }
+            } else {
+                let additional = min(output.capacity(), max_size + 1 - output.len());
+                output.reserve(additional);
+            }
+        }
+    }
+}
+
+/// The TCP stream underlying a possibly TLS-wrapped WebSocket stream.
+fn tcp_stream(stream: &WebSocketStream) -> &TcpStream {
+    match *stream {
+        WebSocketStream::Tcp(ref stream) => stream,
+        WebSocketStream::Ssl(ref stream) => stream.get_ref(),
+    }
+}
+
+/// Takes a connection down, so that reads and writes blocked on it return.
+pub trait Disconnect: Send + Sync + 'static {
+    fn disconnect(&self);
+}
+
+impl Disconnect for TcpStream {
+    fn disconnect(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+/// What a connection exchanges frames over once its opening handshake is
+/// done. `run_connection` only reaches the network through it, so tests can
+/// drive a connection over an in-memory transport instead of a socket.
+pub trait Transport {
+    type Reader: Read + Send + 'static;
+    type Writer: Write + Send + 'static;
+    type Disconnector: Disconnect;
+
+    /// Splits the connection into the halves that frames are read from and
+    /// written to, on different threads, and a way to take it down from any
+    /// thread.
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer, Self::Disconnector)>;
+}
+
+/// The transport of a connection opened over the network.
+struct NetworkTransport {
+    sender: WebSocketSender<WebSocketStream>,
+    receiver: Receiver<WebSocketStream>,
+}
+
+/// Reads the stream of a `Receiver`, including anything that was read ahead
+/// with the handshake response.
+struct ReceiverReader(Receiver<WebSocketStream>);
+
+impl Read for ReceiverReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.get_mut().read(buf)
+    }
+}
+
+/// Writes to the stream of a `Sender`.
+struct SenderWriter(WebSocketSender<WebSocketStream>);
+
+impl Write for SenderWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.get_mut().flush()
+    }
+}
+
+impl Transport for NetworkTransport {
+    type Reader = ReceiverReader;
+    type Writer = SenderWriter;
+    type Disconnector = TcpStream;
+
+    fn split(self) -> io::Result<(ReceiverReader, SenderWriter, TcpStream)> {
+        let stream = try!(tcp_stream(self.sender.get_ref()).try_clone());
+        Ok((ReceiverReader(self.receiver), SenderWriter(self.sender), stream))
+    }
+}
+
+/// The address to connect to for `host`: its hostname, without the brackets
+/// around IPv6 addresses, and its port, or the default one for the scheme.
+pub fn socket_address(host: &Host, secure: bool) -> (&str, u16) {
+    let hostname = &*host.hostname;
+    let hostname = if hostname.starts_with("[") && hostname.ends_with("]") {
+        &hostname[1..hostname.len() - 1]
+    } else {
+        hostname
+    };
+    (hostname, host.port.unwrap_or(if secure { 443 } else { 80 }))
+}
+
+/// Why a connection could not be established. Script only ever sees a
+/// generic failure; the category is for diagnostics.
+#[derive(Debug)]
+pub enum ConnectionError {
+    /// The host name could not be resolved.
+    HostNotFound(io::Error),
+    /// The host, or the proxy, refused the connection.
+    ConnectionRefused(io::Error),
+    /// The TLS handshake failed, or the certificate was rejected.
+    Tls(WebSocketError),
+    /// The server doesn't speak our version of the protocol, and answered
+    /// with the versions it supports, if any.
+    UnsupportedVersion(Option<String>),
+    /// Anything else, usually a failed WebSocket handshake.
+    Other(WebSocketError),
+    /// The connection was given up on before it was established.
+    Aborted,
+}
+
+impl ConnectionError {
+    /// Whether the host name couldn't be resolved, which may be a passing
+    /// network problem. A host that refused the connection, or a server that
+    /// answered the handshake, won't answer differently the next time.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            ConnectionError::HostNotFound(_) => true,
+            ConnectionError::ConnectionRefused(_) | ConnectionError::Tls(_) |
+            ConnectionError::UnsupportedVersion(_) | ConnectionError::Other(_) | ConnectionError::Aborted => false,
+        }
+    }
+}
+
+impl From<WebSocketError> for ConnectionError {
+    fn from(error: WebSocketError) -> ConnectionError {
+        match error {
+            WebSocketError::IoError(error) => ConnectionError::from(error),
+            WebSocketError::SslError(error) => ConnectionError::from(error),
+            error => ConnectionError::Other(error),
+        }
+    }
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(error: io::Error) -> ConnectionError {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => ConnectionError::ConnectionRefused(error),
+            _ => ConnectionError::Other(WebSocketError::IoError(error)),
+        }
+    }
+}
+
+impl From<SslError> for ConnectionError {
+    fn from(error: SslError) -> ConnectionError {
+        ConnectionError::Tls(WebSocketError::SslError(error))
+    }
+}
+
+/// Resolves `hostname`, giving up after `timeout_ms` milliseconds. The
+/// resolver can't be interrupted, so it runs on a thread of its own, which is
+/// left running after a timeout.
+pub fn resolve(hostname: &str, port: u16, timeout_ms: u32) -> io::Result<Vec<SocketAddr>> {
+    let resolved = Arc::new((Mutex::new(None), Condvar::new()));
+    let thread_resolved = resolved.clone();
+    let thread_hostname = hostname.to_owned();
+    spawn_named(format!("WebSocket resolver for {}", hostname), move || {
+        let addresses: io::Result<Vec<SocketAddr>> =
+            (&*thread_hostname, port).to_socket_addrs().map(|addresses| addresses.collect());
+        let (ref result, ref changed) = *thread_resolved;
+        *result.lock().unwrap() = Some(addresses);
+        changed.notify_all();
+    });
+
+    let deadline_ns = time::precise_time_ns() + timeout_ms as u64 * 1_000_000;
+    let (ref result, ref changed) = *resolved;
+    let mut result = result.lock().unwrap();
+    loop {
+        let addresses = result.take();
+        if let Some(addresses) = addresses {
+            return addresses;
+        }
+        let now_ns = time::precise_time_ns();
+        if now_ns >= deadline_ns {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Resolving the host name timed out"));
+        }
+        let remaining_ms = ((deadline_ns - now_ns + 999_999) / 1_000_000) as u32;
+        result = changed.wait_timeout_ms(result, remaining_ms).unwrap().0;
+    }
+}
+
+/// Opens a TCP connection to `hostname` on `port`, trying each address it
+/// resolves to in turn. A failure to resolve it, or a resolver that takes
+/// too long, is told apart from a failure to connect.
+pub fn connect_tcp(hostname: &str, port: u16) -> Result<TcpStream, ConnectionError> {
+    let addresses = try!(resolve(hostname, port, RESOLVE_TIMEOUT_MS).map_err(ConnectionError::HostNotFound));
+    let mut last_error = io::Error::new(io::ErrorKind::Other, "Host has no addresses");
+    for address in addresses {
+        match TcpStream::connect(address) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(ConnectionError::from(last_error))
+}
+
+/// Delays between attempts to reconnect, growing exponentially with jitter,
+/// for embedders that reopen dropped connections themselves. WebSockets
+/// never reconnect on their own, so this isn't reachable from script.
+pub struct Backoff {
+    rng: OsRng,
+    base_ms: u32,
+    max_ms: u32,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(base_ms: u32, max_ms: u32) -> io::Result<Backoff> {
+        Ok(Backoff {
+            rng: try!(OsRng::new()),
+            base_ms: base_ms,
+            max_ms: max_ms,
+            attempts: 0,
+        })
+    }
+
+    /// The longest the next delay can be: `base_ms` doubled for every attempt
+    /// so far, up to `max_ms`.
+    pub fn ceiling(&self) -> u32 {
+        if self.attempts >= 32 {
+            return self.max_ms;
+        }
+        min((self.base_ms as u64) << self.attempts, self.max_ms as u64) as u32
+    }
+
+    /// The delay before the next attempt, somewhere between half the ceiling
+    /// and the ceiling, so that clients dropped together don't all come back
+    /// at once.
+    pub fn next_delay(&mut self) -> u32 {
+        let ceiling = self.ceiling();
+        let half = ceiling / 2;
+        self.attempts = self.attempts.saturating_add(1);
+        half + self.rng.gen_range(0, ceiling - half + 1)
+    }
+
+    /// Starts over from `base_ms`, once a connection succeeded.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// The TLS configuration of wss: connections. Certificate validation
+/// failures make the connection fail, like any other handshake error, unless
+/// `--no-ssl` disabled certificate verification, as for HTTP.
+fn ssl_context() -> WebSocketResult<SslContext> {
+    let mut context = try!(SslContext::new(SslMethod::Sslv23));
+    if opts::get().nossl {
+        context.set_verify(SSL_VERIFY_NONE, None);
+    } else {
+        context.set_verify(SSL_VERIFY_PEER, None);
+        try!(context.set_CA_file(&resources_dir_path().join("certs")));
+    }
+    Ok(context)
+}
+
+/// Whether a certificate issued to `name`, whose leftmost label may be a `*`
+/// wildcard, is valid for `hostname`. Names are compared case-insensitively.
+// https://tools.ietf.org/html/rfc6125#section-6.4
+pub fn certificate_name_matches(name: &str, hostname: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    let hostname = hostname.to_ascii_lowercase();
+    if name.starts_with("*.") {
+        // The wildcard stands for exactly one label.
+        match hostname.find('.') {
+            Some(dot) => dot > 0 && hostname[dot..] == name[1..],
+            None => false,
+        }
+    } else {
+        name == hostname
+    }
+}
+
+/// Fails unless the certificate the server presented was issued to
+/// `hostname`. Validating the certificate chain doesn't check this.
+fn verify_certificate_name(stream: &SslStream<TcpStream>, hostname: &str) -> Result<(), ConnectionError> {
+    let name = stream.ssl().peer_certificate().and_then(|certificate| {
+        certificate.subject_name().text_by_nid(Nid::CN).map(|name| (*name).to_owned())
+    });
+    match name {
+        Some(ref name) if certificate_name_matches(name, hostname) => Ok(()),
+        _ => Err(ConnectionError::Tls(WebSocketError::ResponseError("Certificate was not issued to the host"))),
+    }
+}
+
+/// The longest response to a CONNECT request that is accepted from a proxy.
+const MAX_PROXY_RESPONSE_LENGTH: usize = 8192;
+
+/// Opens a tunnel to `authority` (`host:port`) through the HTTP proxy at
+/// `proxy`, using the CONNECT method. Any response other than a success,
+/// including a request for proxy authentication, fails the connection. The
+/// returned stream keeps `timeout` as its read and write timeouts, and is
+/// shut down if the connection is aborted.
+pub fn connect_through_proxy(proxy: &str, authority: &str, timeout: Duration, abort: &ConnectionAbort)
+                             -> Result<TcpStream, ConnectionError> {
+    let mut stream = try!(TcpStream::connect(proxy));
+    try!(abort.watch(&stream));
+    try!(stream.set_read_timeout(Some(timeout)));
+    try!(stream.set_write_timeout(Some(timeout)));
+    try!(write!(stream, "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", authority));
+
+    // Read the response a byte at a time, so that nothing the origin sends
+    // through the tunnel is consumed here.
+    let mut response = vec![];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() == MAX_PROXY_RESPONSE_LENGTH {
+            return Err(ConnectionError::Other(WebSocketError::ResponseError("Proxy response is too long")));
+        }
+        let mut byte = [0];
+        if try!(stream.read(&mut byte)) == 0 {
+            return Err(ConnectionError::Other(WebSocketError::ResponseError("Proxy closed the connection")));
+        }
+        response.push(byte[0]);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.split(' ').nth(1).and_then(|status| status.parse::<u16>().ok());
+    match status {
+        Some(status) if status >= 200 && status < 300 => {}
+        _ => return Err(ConnectionError::Other(WebSocketError::ResponseError("Proxy refused to open a tunnel"))),
+    }
+
+    Ok(stream)
+}
+
+/// Lets a connection be given up on from another thread while it is being
+/// established: its socket is shut down, which fails whichever step of the
+/// handshake is waiting on the network.
+pub struct ConnectionAbort {
+    aborted: ClosedFlag,
+    stream: Mutex<Option<TcpStream>>,
+}
+
+impl ConnectionAbort {
+    pub fn new() -> ConnectionAbort {
+        ConnectionAbort {
+            aborted: ClosedFlag::new(),
+            stream: Mutex::new(None),
+        }
+    }
+
+    pub fn abort(&self) {
+        let stream = self.stream.lock().unwrap();
+        self.aborted.set();
+        if let Some(ref stream) = *stream {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.is_set()
+    }
+
+    /// Waits up to `timeout_ms` milliseconds for the connection to be
+    /// aborted, and returns whether it is.
+    pub fn wait_ms(&self, timeout_ms: u32) -> bool {
+        self.aborted.wait_ms(timeout_ms)
+    }
+
+    /// Shuts `stream` down when the connection is aborted, or right away if
+    /// it already was.
+    fn watch(&self, stream: &TcpStream) -> Result<(), ConnectionError> {
+        let mut watched = self.stream.lock().unwrap();
+        if self.aborted.is_set() {
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(ConnectionError::Aborted);
+        }
+        *watched = Some(try!(stream.try_clone()));
+        Ok(())
+    }
+}
+
+/// Counts the connections that are still establishing, per host and port, so
+/// that new connections to a host and port wait while too many are pending.
+// https://tools.ietf.org/html/rfc6455#section-4.1
+// Client requirements, step 2
+pub struct PendingConnections {
+    limit: usize,
+    counts: Mutex<HashMap<(String, u16), usize>>,
+    changed: Condvar,
+}
+
+impl PendingConnections {
+    pub fn new(limit: usize) -> PendingConnections {
+        PendingConnections {
+            limit: limit,
+            counts: Mutex::new(HashMap::new()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Blocks until a connection to `port` on `host` may be established. The
+    /// returned guard lets the next one proceed when dropped. Returns `None`
+    /// if the connection is aborted first, once `wake_waiting` is called.
+    pub fn wait_for_turn(&self, host: &str, port: u16, abort: &ConnectionAbort) -> Option<PendingConnection> {
+        let key = (host.to_owned(), port);
+        let mut counts = self.counts.lock().unwrap();
+        while counts.get(&key).map_or(false, |&count| count >= self.limit) {
+            if abort.is_aborted() {
+                return None;
+            }
+            counts = self.changed.wait(counts).unwrap();
+        }
+        *counts.entry(key.clone()).or_insert(0) += 1;
+        Some(PendingConnection {
+            connections: self,
+            key: key,
+        })
+    }
+
+    /// Wakes the connections that wait for their turn, so that the ones that
+    /// were aborted stop waiting.
+    pub fn wake_waiting(&self) {
+        let _counts = self.counts.lock().unwrap();
+        self.changed.notify_all();
+    }
+}
+
+/// A connection counted in `PendingConnections`, until it is dropped.
+pub struct PendingConnection<'a> {
+    connections: &'a PendingConnections,
+    key: (String, u16),
+}
+
+impl<'a> Drop for PendingConnection<'a> {
+    fn drop(&mut self) {
+        let mut counts = self.connections.counts.lock().unwrap();
+        let last = {
+            let count = counts.get_mut(&self.key).unwrap();
+            *count -= 1;
+            *count == 0
+        };
+        if last {
+            counts.remove(&self.key);
+        }
+        self.connections.changed.notify_all();
+    }
+}
+
+/// Whether one of the comma-separated values of the `name` header of
+/// `headers` is `token`, compared case-insensitively.
+fn header_has_token(headers: &Headers, name: &str, token: &str) -> bool {
+    headers.get_raw(name).map_or(false, |lines| lines.iter().any(|line| {
+        String::from_utf8_lossy(line).split(',').any(|value| value.trim().eq_ignore_ascii_case(token))
+    }))
+}
+
+/// Whether the handshake response asks to upgrade the connection to the
+/// WebSocket protocol, with both an `Upgrade: websocket` and a
+/// `Connection: Upgrade` header.
+pub fn upgrade_headers_are_valid(headers: &Headers) -> bool {
+    header_has_token(headers, "Upgrade", "websocket") && header_has_token(headers, "Connection", "Upgrade")
+}
+
+/// Validates the opening handshake response of the server: the checks of
+/// `Response::validate`, and the `Upgrade` and `Connection` headers, whose
+/// values it doesn't compare case-insensitively or as lists.
+fn validate_handshake_response(response: &Response<WebSocketStream>) -> Result<(), ConnectionError> {
+    try!(response.validate());
+    // https://tools.ietf.org/html/rfc6455#section-4.1
+    // Client handshake requirements, steps 2 and 3
+    if !upgrade_headers_are_valid(&response.headers) {
+        return Err(ConnectionError::Other(
+            WebSocketError::ResponseError("Upgrade or Connection header is missing or invalid")));
+    }
+    Ok(())
+}
+
+/// Whether the `Sec-WebSocket-Protocol` values of a handshake response name
+/// exactly one of the `offered` protocols. Protocols are case-sensitive.
+pub fn is_offered_protocol(selected: &[String], offered: &[String]) -> bool {
+    selected.len() == 1 && offered.contains(&selected[0])
+}
+
+/// *Establish a WebSocket Connection* as defined in RFC 6455.
+///
+/// Fails if the server, or the proxy, stops responding for
+/// `handshake_timeout` at any point before the opening handshake completes,
+/// including during the TLS handshake, or as soon as `abort` is aborted.
+pub fn establish_a_websocket_connection(url: (Host, String, bool), origin: Option<String>,
+                                    protocols: Vec<String>, cookies: Option<String>,
+                                    user_agent: Option<String>, handshake_timeout: Duration,
+                                    abort: &ConnectionAbort)
+    -> Result<(Headers, Headers, Option<DeflateParams>, WebSocketSender<WebSocketStream>,
+               Receiver<WebSocketStream>), ConnectionError> {
+    let (host, resource_name, secure) = url;
+    let (hostname, port) = socket_address(&host, secure);
+    let stream = match opts::get().proxy {
+        Some(ref proxy) => {
+            // The authority of a CONNECT request keeps the brackets around
+            // IPv6 addresses.
+            let authority = format!("{}:{}", host.hostname, port);
+            try!(connect_through_proxy(proxy, &authority, handshake_timeout, abort))
+        }
+        None => {
+            let stream = try!(connect_tcp(hostname, port));
+            try!(abort.watch(&stream));
+            try!(stream.set_read_timeout(Some(handshake_timeout)));
+            try!(stream.set_write_timeout(Some(handshake_timeout)));
+            stream
+        }
+    };
+    let stream = if secure {
+        let stream = try!(SslStream::new(&try!(ssl_context()), stream));
+        if !opts::get().nossl {
+            try!(verify_certificate_name(&stream, hostname));
+        }
+        WebSocketStream::Ssl(stream)
+    } else {
+        WebSocketStream::Tcp(stream)
+    };
+    let mut request = try!(Request::new((host.clone(), resource_name, secure), try!(stream.try_clone()), stream));
+    if let Some(origin) = origin {
+        request.headers.set(Origin(origin));
+    }
+    if let Some(user_agent) = user_agent {
+        request.headers.set(UserAgent(user_agent));
+    }
+    if !protocols.is_empty() {
+        request.headers.set(WebSocketProtocol(protocols.clone()));
+    }
+    request.headers.set(WebSocketExtensions(vec![Extension::new(PERMESSAGE_DEFLATE.to_owned())]));
+    // https://tools.ietf.org/html/rfc6455#section-4.1
+    // Client handshake requirements, step 11
+    if let Some(cookies) = cookies {
+        request.headers.set_raw("Cookie".to_owned(), vec![cookies.into_bytes()]);
+    }
+
+    let request_headers = request.headers.clone();
+
+    let response = try!(request.send());
+    // https://tools.ietf.org/html/rfc6455#section-4.4
+    // We only speak version 13, so there is nothing to fall back to.
+    if response.status == StatusCode::UpgradeRequired {
+        let versions = response.headers.get_raw("Sec-WebSocket-Version").map(|lines| {
+            lines.iter().map(|line| String::from_utf8_lossy(line).into_owned()).collect::<Vec<_>>().connect(", ")
+        });
+        return Err(ConnectionError::UnsupportedVersion(versions));
+    }
+    try!(validate_handshake_response(&response));
+
+    {
+        let stream = tcp_stream(response.get_reader().get_ref());
+        try!(stream.set_read_timeout(None));
+        try!(stream.set_write_timeout(None));
+    }
+
+    // https://tools.ietf.org/html/rfc6455#section-4.1
+    // Client handshake requirements, step 6
+    if let Some(&WebSocketProtocol(ref protocol_in_use)) = response.headers.get() {
+        if !is_offered_protocol(protocol_in_use, &protocols) {
+            return Err(ConnectionError::Other(
+                WebSocketError::ProtocolError("Server selected a protocol that was not offered")));
+        }
+    }
+
+    // Client handshake requirements, step 5
+    let deflate = match response.headers.get() {
+        Some(&WebSocketExtensions(ref extensions_in_use)) => {
+            try!(negotiate_deflate(extensions_in_use).map_err(|_| {
+                WebSocketError::ProtocolError("Server selected extensions that were not offered")
+            }))
+        }
+        None => None,
+    };
+
+    let headers = response.headers.clone();
+    let (sender, receiver) = response.begin().split();
+    Ok((request_headers, headers, deflate, sender, receiver))
+}
+
+/// Status code reported when a close frame carries no status code.
+pub const NO_STATUS_RECEIVED: u16 = 1005;
+
+/// Status code sent when a message is too big to process.
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Why a frame couldn't be read off the connection.
+#[derive(Debug)]
+pub enum FrameError {
+    /// The connection was lost.
+    Io(io::Error),
+    /// The frame is invalid, and the connection fails with this status code.
+    Invalid(u16),
+}
+
+impl From<io::Error> for FrameError {
+    fn from(error: io::Error) -> FrameError {
+        FrameError::Io(error)
+    }
+}
+
+/// Reads exactly `length` bytes from `reader`. The buffer grows as the data
+/// arrives rather than being allocated up front.
+fn read_payload<R: Read>(reader: &mut R, length: u64) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    try!(reader.take(length).read_to_end(&mut data));
+    if (data.len() as u64) < length {
+        return Err(io::Error::new(io::ErrorKind::Other, "Connection closed in the middle of a frame"));
+    }
+    Ok(data)
+}
+
+/// Reads the next frame sent by the server. A data frame whose payload is
+/// longer than `max_payload_length`, or a control frame that is fragmented or
+/// longer than 125 bytes, is rejected from its header, before any of its
+/// payload is read.
+// https://tools.ietf.org/html/rfc6455#section-5.2
+pub fn read_frame<R: Read>(reader: &mut R, max_payload_length: usize) -> Result<DataFrame, FrameError> {
+    let header = try!(read_payload(reader, 2));
+    let opcode = try!(Opcode::new(header[0] & 0x0f).ok_or(FrameError::Invalid(1002)));
+    // https://tools.ietf.org/html/rfc6455#section-5.1
+    // Frames from the server are never masked.
+    if header[1] & 0x80 != 0 {
+        return Err(FrameError::Invalid(1002));
+    }
+    let length = match header[1] & 0x7f {
+        126 => try!(read_payload(reader, 2)).iter().fold(0, |length, &byte| length << 8 | byte as u64),
+        127 => try!(read_payload(reader, 8)).iter().fold(0, |length, &byte| length << 8 | byte as u64),
+        length => length as u64,
+    };
+    let is_control = header[0] & 0x08 != 0;
+    let finished = header[0] & 0x80 != 0;
+    if is_control && (length > 125 || !finished) {
+        // https://tools.ietf.org/html/rfc6455#section-5.5
+        return Err(FrameError::Invalid(1002));
+    }
+    if length > max_payload_length as u64 {
+        return Err(FrameError::Invalid(MESSAGE_TOO_BIG));
+    }
+    Ok(DataFrame {
+        finished: finished,
+        reserved: [header[0] & 0x40 != 0, header[0] & 0x20 != 0, header[0] & 0x10 != 0],
+        opcode: opcode,
+        data: try!(read_payload(reader, length)),
+    })
+}
+
+/// Appends a frame's payload to the message being reassembled, failing with
+/// `MESSAGE_TOO_BIG` if the message would grow beyond `max_size` bytes.
+pub fn append_fragment(message: &mut Vec<u8>, fragment: &[u8], max_size: usize) -> Result<(), u16> {
+    if fragment.len() > max_size - message.len() {
+        return Err(MESSAGE_TOO_BIG);
+    }
+    message.push_all(fragment);
+    Ok(())
+}
+
+/// Whether an endpoint may send `code` in a close frame. Codes reserved for
+/// reporting local conditions, such as 1005, 1006 and 1015, may not be.
+/// 1012 to 1014 were registered after RFC 6455, for servers restarting,
+/// overloaded or behind a failing gateway.
+// https://tools.ietf.org/html/rfc6455#section-7.4
+// https://www.iana.org/assignments/websocket/websocket.xml#close-code-number
+fn is_valid_received_close_code(code: u16) -> bool {
+    match code {
+        1000...1003 | 1007...1014 | 3000...4999 => true,
+        _ => false,
+    }
+}
+
+/// Splits the payload of a close frame into its status code and reason.
+///
+/// On a malformed payload, returns the status code to fail the connection
+/// with.
+// https://tools.ietf.org/html/rfc6455#section-5.5.1
+pub fn parse_close_payload(data: &[u8]) -> Result<(u16, String), u16> {
+    match data.len() {
+        0 => Ok((NO_STATUS_RECEIVED, "".to_owned())),
+        1 => Err(1002),
+        _ => {
+            let code = (data[0] as u16) << 8 | data[1] as u16;
+            if !is_valid_received_close_code(code) {
+                return Err(1002);
+            }
+            match String::from_utf8(data[2..].to_vec()) {
+                Ok(reason) => Ok((code, reason)),
+                Err(_) => Err(1007),
+            }
+        }
+    }
+}
+
+/// Reads frames off the connection until it ends, reassembling fragmented
+/// messages and reporting each complete one.
+fn receive_loop<R: Read>(mut reader: R,
+                         event_sender: Sender<WebSocketNetworkEvent>,
+                         command_sender: Sender<WebSocketCommand>,
+                         last_activity: Arc<Mutex<u64>>,
+                         max_message_size: usize,
+                         reassembly_buffer: Arc<AtomicUsize>,
+                         mut inflater: Option<Inflater>,
+                         counters: Arc<FrameCounters>,
+                         devtools_chan: Option<Sender<DevtoolsControlMsg>>,
+                         request_id: String) {
+    // https://tools.ietf.org/html/rfc6455#section-7.1.7
+    let fail = |code: u16, reason: &str| {
+        let data = CloseData::new(code, reason.to_owned());
+        let _ = command_sender.send(WebSocketCommand::Close(Some(data)));
+        let _ = event_sender.send(WebSocketNetworkEvent::Fail);
+        send_devtools_event(&devtools_chan, &request_id, NetworkEvent::WebSocketClosed(None));
+    };
+
+    // The opcode, compression and payload of a fragmented message received
+    // so far.
+    let mut fragments: Option<(Opcode, bool, Vec<u8>)> = None;
+    loop {
+        // A frame may fill at most what is left of the message being
+        // reassembled; control frames are limited separately.
+        let max_payload_length = max_message_size - fragments.as_ref().map_or(0, |&(_, _, ref data)| data.len());
+        let frame = match read_frame(&mut reader, max_payload_length) {
+            Ok(frame) => frame,
+            Err(FrameError::Io(e)) => {
+                // The connection was lost without a close frame.
+                debug!("Failed to receive a WebSocket frame: {:?}", e);
+                let _ = event_sender.send(WebSocketNetworkEvent::Fail);
+                send_devtools_event(&devtools_chan, &request_id, NetworkEvent::WebSocketClosed(None));
+                break;
+            }
+            Err(FrameError::Invalid(MESSAGE_TOO_BIG)) => {
+                fail(MESSAGE_TOO_BIG, "Message too big");
+                break;
+            }
+            Err(FrameError::Invalid(code)) => {
+                fail(code, "Invalid frame");
+                break;
+            }
+        };
+        *last_activity.lock().unwrap() = time::precise_time_ns();
+        counters.count_received(frame.data.len());
+
+        // https://tools.ietf.org/html/rfc6455#section-5.2
+        // Only the first frame of a permessage-deflate message has RSV1 set.
+        let compressed = frame.reserved[0];
+        let first_frame = match frame.opcode {
+            Opcode::Text | Opcode::Binary => true,
+            _ => false,
+        };
+        // The frames of a compressed message are reported once it is
+        // inflated, as a whole, so devtools show what the page receives.
+        let part_of_compressed_message = match frame.opcode {
+            Opcode::Text | Opcode::Binary => compressed,
+            Opcode::Continuation => fragments.as_ref().map_or(false, |&(_, compressed, _)| compressed),
+            _ => false,
+        };
+        if !part_of_compressed_message {
+            send_devtools_frame(&devtools_chan, &request_id, WebSocketFrameDirection::Received,
+                                frame.opcode as u8, &frame.data);
+        }
+        if frame.reserved[1] || frame.reserved[2] || (compressed && (inflater.is_none() || !first_frame)) {
+            fail(1002, "Unexpected reserved bit");
+            break;
+        }
+
+        let (opcode, compressed, data) = match frame.opcode {
+            Opcode::Text | Opcode::Binary if fragments.is_none() => {
+                if frame.data.len() > max_message_size {
+                    fail(MESSAGE_TOO_BIG, "Message too big");
+                    break;
+                }
+                if !frame.finished {
+                    reassembly_buffer.store(frame.data.len(), Ordering::SeqCst);
+                    fragments = Some((frame.opcode, compressed, frame.data));
+                    continue;
+                }
+                (frame.opcode, compressed, frame.data)
+            }
+            Opcode::Continuation if fragments.is_some() => {
+                let (opcode, compressed, mut data) = fragments.take().unwrap();
+                if append_fragment(&mut data, &frame.data, max_message_size).is_err() {
+                    fail(MESSAGE_TOO_BIG, "Message too big");
+                    break;
+                }
+                if !frame.finished {
+                    reassembly_buffer.store(data.len(), Ordering::SeqCst);
+                    fragments = Some((opcode, compressed, data));
+                    continue;
+                }
+                reassembly_buffer.store(0, Ordering::SeqCst);
+                (opcode, compressed, data)
+            }
+            Opcode::Text | Opcode::Binary | Opcode::Continuation => {
+                // https://tools.ietf.org/html/rfc6455#section-5.4
+                fail(1002, "Unexpected data frame");
+                break;
+            }
+            // Control frames may be interleaved with the fragments of a
+            // message, and never touch the reassembly buffer.
+            Opcode::Ping => {
+                // https://tools.ietf.org/html/rfc6455#section-5.5.2
+                let _ = command_sender.send(WebSocketCommand::Pong(frame.data));
+                continue;
+            }
+            Opcode::Close => {
+                let (code, reason) = match parse_close_payload(&frame.data) {
+                    Ok(close) => close,
+                    Err(code) => {
+                        fail(code, "Malformed close frame");
+                        break;
+                    }
+                };
+                // Answer with a close frame echoing the status code, unless
+                // one was sent already.
+                // https://tools.ietf.org/html/rfc6455#section-5.5.1
+                let data = if code == NO_STATUS_RECEIVED {
+                    None
+                } else {
+                    Some(CloseData::new(code, "".to_owned()))
+                };
+                let _ = command_sender.send(WebSocketCommand::Close(data));
+                send_devtools_event(&devtools_chan, &request_id, NetworkEvent::WebSocketClosed(Some(code)));
+                let _ = event_sender.send(WebSocketNetworkEvent::Close(code, reason));
+                break;
+            }
+            // Pongs are accepted whether or not we sent a ping.
+            Opcode::Pong => continue,
+            _ => {
+                fail(1002, "Reserved opcode");
+                break;
+            }
+        };
+
+        let data = match inflater.as_mut() {
+            Some(ref mut inflater) if compressed => match inflater.inflate(&data, max_message_size) {
+                Ok(data) => data,
+                Err(code) => {
+                    fail(code, "Invalid compressed message");
+                    break;
+                }
+            },
+            _ => data,
+        };
+        if compressed {
+            send_devtools_frame(&devtools_chan, &request_id, WebSocketFrameDirection::Received,
+                                opcode as u8, &data);
+        }
+
+        let message = match opcode {
+            Opcode::Text => match String::from_utf8(data) {
+                Ok(text) => MessageData::Text(text),
+                Err(_) => {
+                    // https://tools.ietf.org/html/rfc6455#section-8.1
+                    fail(1007, "Invalid UTF-8 in text frame");
+                    break;
+                }
+            },
+            _ => MessageData::Binary(data),
+        };
+        let _ = event_sender.send(WebSocketNetworkEvent::MessageReceived(message));
+    }
+}
+
+/// Set once a connection is closing. Threads can wait for it to be set.
+pub struct ClosedFlag {
+    closed: Mutex<bool>,
+    changed: Condvar,
+}
+
+impl ClosedFlag {
+    pub fn new() -> ClosedFlag {
+        ClosedFlag {
+            closed: Mutex::new(false),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn set(&self) {
+        *self.closed.lock().unwrap() = true;
+        self.changed.notify_all();
+    }
+
+    pub fn is_set(&self) -> bool {
+        *self.closed.lock().unwrap()
+    }
+
+    /// Waits up to `timeout_ms` milliseconds for the flag to be set, and
+    /// returns whether it is.
+    pub fn wait_ms(&self, timeout_ms: u32) -> bool {
+        let deadline_ns = time::precise_time_ns() + timeout_ms as u64 * 1_000_000;
+        let mut closed = self.closed.lock().unwrap();
+        while !*closed {
+            let now_ns = time::precise_time_ns();
+            if now_ns >= deadline_ns {
+                break;
+            }
+            let remaining_ms = ((deadline_ns - now_ns + 999_999) / 1_000_000) as u32;
+            closed = self.changed.wait_timeout_ms(closed, remaining_ms).unwrap().0;
+        }
+        *closed
+    }
+}
+
+/// Queues a ping whenever the connection has been idle for `interval_secs`,
+/// until `closed` is set.
+fn keepalive_loop(interval_secs: u32,
+                  last_activity: Arc<Mutex<u64>>,
+                  closed: Arc<ClosedFlag>,
+                  command_sender: Sender<WebSocketCommand>) {
+    let interval_ns = interval_secs as u64 * 1_000_000_000;
+    loop {
+        if closed.wait_ms(interval_secs * 1000) {
+            return;
+        }
+        // The writer may have recorded activity after the time was taken, so
+        // take it only once the last activity is known.
+        let last_activity_ns = *last_activity.lock().unwrap();
+        let idle_ns = time::precise_time_ns().saturating_sub(last_activity_ns);
+        if idle_ns >= interval_ns && command_sender.send(WebSocketCommand::Ping).is_err() {
+            return;
+        }
+    }
+}
+
+/// Opens the WebSocket connection described by `connect_data`, sending
+/// `cookies` with the handshake and storing the ones the server sets through
+/// `resource_task`. The handshake and all frames are reported to
+/// `devtools_chan`.
+pub fn init(connect: WebSocketCommunicate, connect_data: WebSocketConnectData,
+            cookies: Option<String>, user_agent: Option<String>, resource_task: ResourceTask,
+            pending_connections: Arc<PendingConnections>,
+            devtools_chan: Option<Sender<DevtoolsControlMsg>>) {
+    let thread_url = connect_data.resource_url.serialize();
+    spawn_named(format!("WebSocket connection to {}", thread_url), move || {
+        let WebSocketCommunicate { event_sender, action_receiver } = connect;
+        let WebSocketConnectData { resource_url, origin, protocols, mem_profiler_chan } = connect_data;
+
+        let abort = Arc::new(ConnectionAbort::new());
+        let (handshake_sender, handshake_receiver) = channel();
+        {
+            let resource_url = resource_url.clone();
+            let pending_connections = pending_connections.clone();
+            let abort = abort.clone();
+            spawn_named(format!("WebSocket handshake with {}", thread_url), move || {
+                let connection = parse_url(&resource_url).map_err(ConnectionError::from).and_then(|url| {
+                    let (hostname, port) = socket_address(&url.0, url.2);
+                    let mut retries = 0;
+                    loop {
+                        // Other connections to the host may go ahead while
+                        // this one waits to be retried.
+                        let result = match pending_connections.wait_for_turn(hostname, port, &abort) {
+                            Some(_pending) => {
+                                establish_a_websocket_connection(url.clone(), origin.clone(), protocols.clone(),
+                                                                 cookies.clone(), user_agent.clone(),
+                                                                 Duration::from_secs(HANDSHAKE_TIMEOUT_SECS),
+                                                                 &abort)
+                            }
+                            None => Err(ConnectionError::Aborted),
+                        };
+                        match result {
+                            Err(ref e) if e.is_transient() && retries < CONNECT_RETRIES => {
+                                debug!("Retrying the WebSocket connection after {:?}", e);
+                                retries += 1;
+                                if abort.wait_ms(CONNECT_RETRY_DELAY_MS) {
+                                    return Err(ConnectionError::Aborted);
+                                }
+                            }
+                            result => return result,
+                        }
+                    }
+                });
+                let _ = handshake_sender.send(connection);
+            });
+        }
+
+        // Script only sends an abort while the connection is being
+        // established, and the channel disconnects when the WebSocket object
+        // is gone; either way the handshake is given up on right away rather
+        // than once it completes.
+        {
+            let sel = Select::new();
+            let mut handshake_handle = sel.handle(&handshake_receiver);
+            let mut action_handle = sel.handle(&action_receiver);
+            unsafe {
+                handshake_handle.add();
+                action_handle.add();
+            }
+            sel.wait();
+        }
+        let connection = match handshake_receiver.try_recv() {
+            Ok(connection) => connection,
+            Err(_) => {
+                abort.abort();
+                pending_connections.wake_waiting();
+                Err(ConnectionError::Aborted)
+            }
+        };
+        let (request_headers, headers, deflate, sender, receiver) = match connection {
+            Ok(connection) => connection,
+            Err(e) => {
+                match e {
+                    ConnectionError::HostNotFound(e) => debug!("Failed to resolve the WebSocket host: {:?}", e),
+                    ConnectionError::ConnectionRefused(e) => debug!("WebSocket connection refused: {:?}", e),
+                    ConnectionError::Tls(e) => debug!("Failed to secure the WebSocket connection: {:?}", e),
+                    ConnectionError::UnsupportedVersion(versions) => {
+                        debug!("WebSocket server only supports versions {:?}", versions)
+                    }
+                    ConnectionError::Other(e) => debug!("Failed to establish a WebSocket connection: {:?}", e),
+                    ConnectionError::Aborted => debug!("WebSocket connection aborted during the handshake"),
+                }
+                let _ = event_sender.send(WebSocketNetworkEvent::Fail);
+                return;
+            }
+        };
+
+        let request_id = uuid::Uuid::new_v4().to_simple_string();
+        send_devtools_event(&devtools_chan, &request_id,
+                            NetworkEvent::HttpRequest(resource_url.clone(), Method::Get, request_headers, None));
+        let status = RawStatus(101, Cow::Borrowed("Switching Protocols"));
+        send_devtools_event(&devtools_chan, &request_id,
+                            NetworkEvent::HttpResponse(Some(headers.clone()), Some(status), None));
+
+        if let Some(cookies) = headers.get_raw("set-cookie") {
+            for cookie in cookies.iter() {
+                if let Ok(cookie) = String::from_utf8(cookie.clone()) {
+                    let _ = resource_task.send(ControlMsg::SetCookiesForUrl(resource_url.clone(),
+                                                                            cookie,
+                                                                            CookieSource::HTTP));
+                }
+            }
+        }
+
+        let connect = WebSocketCommunicate {
+            event_sender: event_sender,
+            action_receiver: action_receiver,
+        };
+        let transport = NetworkTransport {
+            sender: sender,
+            receiver: receiver,
+        };
+        run_connection(transport, connect, headers, deflate, resource_url, mem_profiler_chan,
+                       devtools_chan, request_id);
+    });
+}
+
+/// Runs a connection whose opening handshake is done over `transport`, until
+/// it ends: reports it as established with the response `headers`, and then
+/// writes the actions of `connect` to the connection and reports the frames
+/// read from it. `deflate` is what was negotiated for permessage-deflate.
+///
+/// The connection only uses `transport` to reach the network, so tests can
+/// drive it without a server.
+pub fn run_connection<T: Transport>(transport: T, connect: WebSocketCommunicate, headers: Headers,
+                                    deflate: Option<DeflateParams>, resource_url: Url,
+                                    mem_profiler_chan: mem::ProfilerChan,
+                                    devtools_chan: Option<Sender<DevtoolsControlMsg>>, request_id: String) {
+    let thread_url = resource_url.serialize();
+    let WebSocketCommunicate { event_sender, action_receiver } = connect;
+    let (reader, mut writer, disconnector) = match transport.split() {
+        Ok(split) => split,
+        Err(e) => {
+            debug!("Failed to split a WebSocket connection: {:?}", e);
+            let _ = event_sender.send(WebSocketNetworkEvent::Fail);
+            return;
+        }
+    };
+    let disconnector = Arc::new(disconnector);
+
+    let deflater = deflate.map(|params| Deflater::new(params.client_no_context_takeover));
+    let mut frame_writer = match FrameWriter::new(deflater) {
+        Ok(frame_writer) => frame_writer,
+        Err(e) => {
+            debug!("Failed to open the random number generator for masking keys: {:?}", e);
+            disconnector.disconnect();
+            let _ = event_sender.send(WebSocketNetworkEvent::Fail);
+            return;
+        }
+    };
+    let inflater = deflate.map(|params| Inflater::new(params.server_no_context_takeover));
+
+    // close() may have been called during the handshake, or the WebSocket
+    // object may be gone; drop the new connection rather than opening it.
+    // Nothing else can be pending before the connection is reported as
+    // established. The handshake did succeed, so tell the server that the
+    // connection is going away before dropping it, whichever finished first.
+    // https://tools.ietf.org/html/rfc6455#section-7.1.7
+    match action_receiver.try_recv() {
+        Ok(WebSocketDomAction::Abort) | Err(TryRecvError::Disconnected) => {
+            let _ = frame_writer.write_message(&mut writer, &Message::Close(None));
+            disconnector.disconnect();
+            let _ = event_sender.send(WebSocketNetworkEvent::Fail);
+            return;
+        }
+        _ => {}
+    }
+
+    let _ = event_sender.send(WebSocketNetworkEvent::ConnectionEstablished(headers));
+
+    let memory_usage = MemoryUsage::new();
+    let reporter_name = format!("websocket-reporter-{}", request_id);
+    let (reporter_sender, reporter_receiver) = ipc::channel().unwrap();
+    let reporter_memory_usage = memory_usage.clone();
+    ROUTER.add_route(reporter_receiver.to_opaque(), box move |reporter_request| {
+        let reporter_request: ReporterRequest = reporter_request.to().unwrap();
+        reporter_request.reports_channel.send(reporter_memory_usage.reports(&resource_url));
+    });
+    mem_profiler_chan.send(mem::ProfilerMsg::RegisterReporter(reporter_name.clone(),
+                                                              Reporter(reporter_sender)));
+
+    let (command_sender, command_receiver) = channel();
+    let last_activity = Arc::new(Mutex::new(time::precise_time_ns()));
+    // Set once the connection is closing, to stop keepalive pings.
+    let closed = Arc::new(ClosedFlag::new());
+
+    let action_command_sender = command_sender.clone();
+    let send_queue = memory_usage.send_queue.clone();
+    let abort_disconnector = disconnector.clone();
+    spawn_named(format!("WebSocket actions for {}", thread_url), move || {
+        for action in action_receiver.iter() {
+            let abort = match action {
+                WebSocketDomAction::Abort => true,
+                _ => false,
+            };
+            let command = match action {
+                WebSocketDomAction::SendMessage(MessageData::Text(text)) => {
+                    let data_byte_len = text.len() as u32;
+                    send_queue.fetch_add(data_byte_len as usize, Ordering::SeqCst);
+                    WebSocketCommand::SendMessage(Message::Text(text), data_byte_len)
+                }
+                WebSocketDomAction::SendMessage(MessageData::Binary(data)) => {
+                    let data_byte_len = data.len() as u32;
+                    send_queue.fetch_add(data_byte_len as usize, Ordering::SeqCst);
+                    WebSocketCommand::SendMessage(Message::Binary(data), data_byte_len)
+                }
+                WebSocketDomAction::SendBlob(data) => {
+                    let data_byte_len = data.len() as u32;
+                    send_queue.fetch_add(data_byte_len as usize, Ordering::SeqCst);
+                    WebSocketCommand::SendBlob(data, data_byte_len)
+                }
+                WebSocketDomAction::Close(data) => {
+                    WebSocketCommand::Close(data.map(|(code, reason)| CloseData::new(code, reason)))
+                }
+                WebSocketDomAction::Abort => WebSocketCommand::Abort,
+            };
+            if action_command_sender.send(command).is_err() {
+                return;
+            }
+            if abort {
+                // The writer may be stuck writing to a server that doesn't
+                // read; don't let it hold up the abort for long.
+                sleep_ms(ABORT_GRACE_PERIOD_MS);
+                abort_disconnector.disconnect();
+                return;
+            }
+        }
+        // The WebSocket object was collected, so nobody can close the
+        // connection anymore: start the closing handshake, with no status
+        // code, unless it already started.
+        // https://html.spec.whatwg.org/multipage/#garbage-collection-2
+        let _ = action_command_sender.send(WebSocketCommand::Close(None));
+    });
+
+    let receive_event_sender = event_sender.clone();
+    let receive_command_sender = command_sender.clone();
+    let receive_last_activity = last_activity.clone();
+    let receive_closed = closed.clone();
+    let receive_reassembly_buffer = memory_usage.reassembly_buffer.clone();
+    let receive_counters = frame_writer.counters();
+    let receive_devtools_chan = devtools_chan.clone();
+    let receive_request_id = request_id.clone();
+    spawn_named(format!("WebSocket receive loop for {}", thread_url), move || {
+        receive_loop(reader, receive_event_sender, receive_command_sender.clone(),
+                     receive_last_activity, MAX_MESSAGE_SIZE, receive_reassembly_buffer,
+                     inflater, receive_counters, receive_devtools_chan, receive_request_id);
+        receive_closed.set();
+        let _ = receive_command_sender.send(WebSocketCommand::ReceiveEnded);
+    });
+
+    if KEEPALIVE_INTERVAL_SECS != 0 {
+        let keepalive_last_activity = last_activity.clone();
+        let keepalive_closed = closed.clone();
+        spawn_named(format!("WebSocket keepalive for {}", thread_url), move || {
+            keepalive_loop(KEEPALIVE_INTERVAL_SECS, keepalive_last_activity, keepalive_closed,
+                           command_sender);
+        });
+    }
+
+    // Writes happen here rather than on the script task, so that send()
+    // never blocks on the network.
+    let mut batch = WriteBatch::new();
+    // Only one close frame is ever sent.
+    let mut close_sent = false;
+    while let Ok(command) = command_receiver.recv() {
+        let mut command = Some(command);
+        let mut abort = false;
+        let mut receive_ended = false;
+        // A large blob, which is written in chunks after the batch rather
+        // than copied into it.
+        let mut chunked = None;
+        // Coalesce the frames of the commands that are already queued into a
+        // single write.
+        while let Some(current) = command.take() {
+            match current {
+                WebSocketCommand::SendMessage(message, data_byte_len) => {
+                    send_devtools_message(&devtools_chan, &request_id, &message);
+                    batch.push(&mut frame_writer, &message, Some(data_byte_len));
+                    memory_usage.send_queue.fetch_sub(data_byte_len as usize, Ordering::SeqCst);
+                }
+                WebSocketCommand::SendBlob(data, data_byte_len) => {
+                    send_devtools_frame(&devtools_chan, &request_id, WebSocketFrameDirection::Sent,
+                                        Opcode::Binary as u8, &data);
+                    if frame_writer.can_write_in_chunks() && data.len() > WRITE_BATCH_LIMIT {
+                        chunked = Some((data, data_byte_len));
+                    } else {
+                        batch.push_binary(&mut frame_writer, &data, data_byte_len);
+                        memory_usage.send_queue.fetch_sub(data_byte_len as usize, Ordering::SeqCst);
+                    }
+                }
+                WebSocketCommand::Pong(data) => {
+                    let message = Message::Pong(data);
+                    send_devtools_message(&devtools_chan, &request_id, &message);
+                    batch.push(&mut frame_writer, &message, None);
+                }
+                WebSocketCommand::Ping => {
+                    if !closed.is_set() {
+                        let message = Message::Ping(vec![]);
+                        send_devtools_message(&devtools_chan, &request_id, &message);
+                        batch.push(&mut frame_writer, &message, None);
+                    }
+                }
+                WebSocketCommand::Close(data) => {
+                    // Commands are handled in the order they were queued, so
+                    // every message sent before close() is written first.
+                    closed.set();
+                    if !close_sent {
+                        close_sent = true;
+                        let message = Message::Close(data);
+                        send_devtools_message(&devtools_chan, &request_id, &message);
+                        batch.push(&mut frame_writer, &message, None);
+                    }
+                }
+                WebSocketCommand::Abort => {
+                    // Tell the server that the connection is going away
+                    // before dropping it, as when aborting before it opened.
+                    abort = true;
+                    closed.set();
+                    if !close_sent {
+                        close_sent = true;
+                        let message = Message::Close(None);
+                        send_devtools_message(&devtools_chan, &request_id, &message);
+                        batch.push(&mut frame_writer, &message, None);
+                    }
+                }
+                WebSocketCommand::ReceiveEnded => receive_ended = true,
+            }
+            if !abort && !receive_ended && chunked.is_none() && !batch.is_full() {
+                command = command_receiver.try_recv().ok();
+            }
+        }
+        let mut result = batch.flush(&mut writer);
+        if let Some((data, data_byte_len)) = chunked {
+            result = result.and_then(|mut sent| {
+                try!(frame_writer.write_binary_in_chunks(&mut writer, &data));
+                sent.push(data_byte_len);
+                Ok(sent)
+            });
+            memory_usage.send_queue.fetch_sub(data_byte_len as usize, Ordering::SeqCst);
+        }
+        match result {
+            // bufferedAmount keeps counting data that was never sent.
+            Ok(sent) => {
+                for data_byte_len in sent {
+                    let _ = event_sender.send(WebSocketNetworkEvent::MessageSent(data_byte_len));
+                }
+            }
+            Err(e) => {
+                // Take the connection down, so that the receive loop reports
+                // the failure.
+                debug!("Failed to write to a WebSocket connection: {:?}", e);
+                closed.set();
+                disconnector.disconnect();
+            }
+        }
+        if abort || receive_ended {
+            // Once the receive loop ended, the close frames of both ends
+            // have been exchanged, or the connection was lost. After an
+            // abort, the receive loop reports the failure once the
+            // connection is down.
+            closed.set();
+            disconnector.disconnect();
+            break;
+        }
+        *last_activity.lock().unwrap() = time::precise_time_ns();
+    }
+
+    let counters = frame_writer.counters();
+    debug!("WebSocket connection to {} sent {} frames ({} bytes) and received {} frames ({} bytes)",
+           thread_url, counters.frames_sent.load(Ordering::SeqCst), counters.bytes_sent.load(Ordering::SeqCst),
+           counters.frames_received.load(Ordering::SeqCst), counters.bytes_received.load(Ordering::SeqCst));
+    mem_profiler_chan.send(mem::ProfilerMsg::UnregisterReporter(reporter_name));
+}
@@ -23,14 +23,17 @@ extern crate stb_image;
 extern crate url;
 extern crate util;
 extern crate msg;
+extern crate profile_traits;
 
 use hyper::header::{ContentType, Headers};
 use hyper::http::RawStatus;
 use hyper::method::Method;
 use hyper::mime::{Mime, Attr};
 use msg::constellation_msg::{PipelineId};
+use profile_traits::mem::ProfilerChan;
 use url::Url;
 
+use std::sync::Arc;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
 
@@ -128,9 +131,63 @@ pub enum IncludeSubdomains {
     NotIncluded
 }
 
+/// The data of a WebSocket message.
+#[derive(Clone)]
+pub enum MessageData {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Actions a WebSocket object asks its connection to perform.
+pub enum WebSocketDomAction {
+    /// Send a message.
+    SendMessage(MessageData),
+    /// Send the data of a Blob as a binary message. The data is shared with
+    /// the Blob, and only copied on the connection's side.
+    SendBlob(Arc<Vec<u8>>),
+    /// Start the closing handshake, with an optional status code and reason.
+    Close(Option<(u16, String)>),
+    /// Drop a connection that `close()` gave up on while it was being
+    /// established.
+    Abort,
+}
+
+/// Events a WebSocket connection reports to its WebSocket object.
+pub enum WebSocketNetworkEvent {
+    /// The opening handshake completed; carries the server's response headers.
+    ConnectionEstablished(Headers),
+    /// A complete message was received.
+    MessageReceived(MessageData),
+    /// A message of the given byte length was written to the connection.
+    MessageSent(u32),
+    /// The server sent a close frame with the given status code and reason.
+    Close(u16, String),
+    /// The connection failed, or was lost without a closing handshake.
+    Fail,
+}
+
+/// The channels a WebSocket object and its connection communicate over.
+pub struct WebSocketCommunicate {
+    pub event_sender: Sender<WebSocketNetworkEvent>,
+    pub action_receiver: Receiver<WebSocketDomAction>,
+}
+
+/// What the resource task needs to know to open a WebSocket connection.
+pub struct WebSocketConnectData {
+    pub resource_url: Url,
+    /// The ASCII serialization of the requesting origin, or `None` if it is
+    /// opaque.
+    pub origin: Option<String>,
+    pub protocols: Vec<String>,
+    /// The memory profiler to report the connection's buffers to.
+    pub mem_profiler_chan: ProfilerChan,
+}
+
 pub enum ControlMsg {
     /// Request the data associated with a particular URL
     Load(LoadData, LoadConsumer),
+    /// Open a WebSocket connection
+    WebsocketConnect(WebSocketCommunicate, WebSocketConnectData),
     /// Store a set of cookies for a given originating URL
     SetCookiesForUrl(Url, String, CookieSource),
     /// Retrieve the stored cookies for a given URL
@@ -532,7 +532,7 @@ impl FromJSValConvertible for USVString {
             let chars = JS_GetTwoByteStringCharsAndLength(cx, ptr::null(), jsstr, &mut length);
             assert!(!chars.is_null());
             let char_vec = slice::from_raw_parts(chars as *const u16, length as usize);
-            Ok(USVString(String::from_utf16_lossy(char_vec)))
+            Ok(USVString::from_utf16(char_vec))
         }
     }
 }
@@ -155,3 +155,10 @@ impl ops::Deref for ByteString {
 /// A string that is constructed from a UCS-2 buffer by replacing invalid code
 /// points with the replacement character.
 pub struct USVString(pub String);
+
+impl USVString {
+    /// Converts a UTF-16 buffer, replacing lone surrogates with U+FFFD.
+    pub fn from_utf16(units: &[u16]) -> USVString {
+        USVString(String::from_utf16_lossy(units))
+    }
+}
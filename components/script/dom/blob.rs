@@ -9,6 +9,7 @@ use dom::bindings::utils::{Reflector, reflect_dom_object};
 use dom::bindings::error::Fallible;
 use dom::bindings::codegen::Bindings::BlobBinding;
 use dom::bindings::codegen::Bindings::BlobBinding::BlobMethods;
+use std::sync::Arc;
 use std::sync::mpsc::Sender;
 use util::str::DOMString;
 use num::ToPrimitive;
@@ -28,7 +29,7 @@ pub enum BlobTypeId {
 pub struct Blob {
     reflector_: Reflector,
     type_: BlobTypeId,
-    bytes: Option<Vec<u8>>,
+    bytes: Option<Arc<Vec<u8>>>,
     typeString: DOMString,
     global: GlobalField,
     isClosed_: Cell<bool>
@@ -46,7 +47,7 @@ impl Blob {
         Blob {
             reflector_: Reflector::new(),
             type_: type_,
-            bytes: bytes,
+            bytes: bytes.map(Arc::new),
             typeString: typeString.to_owned(),
             global: GlobalField::from_rooted(&global),
             isClosed_: Cell::new(false)
@@ -81,11 +82,18 @@ impl Blob {
 
 pub trait BlobHelpers {
     fn read_out_buffer(self, send: Sender<Vec<u8>>);
+    fn shared_bytes(self) -> Arc<Vec<u8>>;
 }
 
 impl<'a> BlobHelpers for &'a Blob {
     fn read_out_buffer(self, send: Sender<Vec<u8>>) {
-        send.send(self.bytes.clone().unwrap_or(vec![])).unwrap();
+        send.send(self.bytes.as_ref().map_or(vec![], |bytes| (**bytes).clone())).unwrap();
+    }
+
+    /// The data of this Blob, without copying it, for reading it on another
+    /// thread.
+    fn shared_bytes(self) -> Arc<Vec<u8>> {
+        self.bytes.clone().unwrap_or(Arc::new(vec![]))
     }
 }
 
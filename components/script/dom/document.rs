@@ -19,7 +19,7 @@ use dom::bindings::codegen::InheritTypes::{HTMLElementCast, HTMLHeadElementCast,
 use dom::bindings::codegen::InheritTypes::{DocumentTypeCast, HTMLHtmlElementCast, NodeCast};
 use dom::bindings::codegen::InheritTypes::{EventTargetCast, HTMLAnchorElementCast};
 use dom::bindings::codegen::InheritTypes::{HTMLAnchorElementDerived, HTMLAppletElementDerived};
-use dom::bindings::codegen::InheritTypes::{HTMLAreaElementDerived, HTMLEmbedElementDerived};
+use dom::bindings::codegen::InheritTypes::{HTMLAreaElementDerived, HTMLBaseElementDerived, HTMLEmbedElementDerived};
 use dom::bindings::codegen::InheritTypes::{HTMLFormElementDerived, HTMLImageElementDerived};
 use dom::bindings::codegen::InheritTypes::{HTMLScriptElementDerived, HTMLTitleElementDerived};
 use dom::bindings::codegen::InheritTypes::ElementDerived;
@@ -85,7 +85,7 @@ use euclid::point::Point2D;
 use html5ever::tree_builder::{QuirksMode, NoQuirks, LimitedQuirks, Quirks};
 use layout_interface::{LayoutChan, Msg};
 use string_cache::{Atom, QualName};
-use url::Url;
+use url::{Url, UrlParser};
 use js::jsapi::{JSContext, JSObject, JSRuntime};
 
 use num::ToPrimitive;
@@ -229,6 +229,7 @@ pub trait DocumentHelpers<'a> {
     fn is_html_document(self) -> bool;
     fn is_fully_active(self) -> bool;
     fn url(self) -> Url;
+    fn base_url(self) -> Url;
     fn quirks_mode(self) -> QuirksMode;
     fn set_quirks_mode(self, mode: QuirksMode);
     fn set_encoding_name(self, name: DOMString);
@@ -337,6 +338,22 @@ impl<'a> DocumentHelpers<'a> for &'a Document {
         self.url.clone()
     }
 
+    // https://html.spec.whatwg.org/multipage/#document-base-url
+    fn base_url(self) -> Url {
+        let base = NodeCast::from_ref(self).traverse_preorder()
+                                          .filter(|node| node.r().is_htmlbaseelement())
+                                          .filter_map(ElementCast::to_root)
+                                          .find(|element| element.r().has_attribute(&atom!("href")));
+        match base {
+            None => self.url(),
+            // https://html.spec.whatwg.org/multipage/#frozen-base-url
+            Some(base) => {
+                let href = base.r().get_string_attribute(&atom!("href"));
+                UrlParser::new().base_url(&self.url).parse(&href).unwrap_or(self.url())
+            }
+        }
+    }
+
     fn quirks_mode(self) -> QuirksMode {
         self.quirks_mode.get()
     }
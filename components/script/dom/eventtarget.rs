@@ -7,6 +7,7 @@ use dom::bindings::cell::DOMRefCell;
 use dom::bindings::codegen::Bindings::EventHandlerBinding::EventHandlerNonNull;
 use dom::bindings::codegen::Bindings::EventListenerBinding::EventListener;
 use dom::bindings::codegen::Bindings::EventTargetBinding::EventTargetMethods;
+use dom::bindings::codegen::InheritTypes::WebSocketCast;
 use dom::bindings::error::{Fallible, report_pending_exception};
 use dom::bindings::error::Error::InvalidState;
 use dom::bindings::utils::{Reflectable, Reflector};
@@ -145,6 +146,14 @@ impl EventTarget {
     pub fn type_id<'a>(&'a self) -> &'a EventTargetTypeId {
         &self.type_id
     }
+
+    /// Called after listeners have been added or removed.
+    fn listeners_changed(&self) {
+        // Whether a WebSocket may be collected depends on its listeners.
+        if let Some(ws) = WebSocketCast::to_ref(self) {
+            ws.update_pin();
+        }
+    }
 }
 
 pub trait EventTargetHelpers {
@@ -167,6 +176,7 @@ pub trait EventTargetHelpers {
     fn get_event_handler_common<T: CallbackContainer>(self, ty: &str) -> Option<Rc<T>>;
 
     fn has_handlers(self) -> bool;
+    fn has_listeners_for(self, type_: &str) -> bool;
 }
 
 impl<'a> EventTargetHelpers for &'a EventTarget {
@@ -183,37 +193,40 @@ impl<'a> EventTargetHelpers for &'a EventTarget {
     fn set_inline_event_listener(self,
                                  ty: DOMString,
                                  listener: Option<Rc<EventListener>>) {
-        let mut handlers = self.handlers.borrow_mut();
-        let entries = match handlers.entry(ty) {
-            Occupied(entry) => entry.into_mut(),
-            Vacant(entry) => entry.insert(vec!()),
-        };
-
-        let idx = entries.iter().position(|ref entry| {
-            match entry.listener {
-                EventListenerType::Inline(_) => true,
-                _ => false,
-            }
-        });
-
-        match idx {
-            Some(idx) => {
-                match listener {
-                    Some(listener) => entries[idx].listener = EventListenerType::Inline(listener),
-                    None => {
-                        entries.remove(idx);
+        {
+            let mut handlers = self.handlers.borrow_mut();
+            let entries = match handlers.entry(ty) {
+                Occupied(entry) => entry.into_mut(),
+                Vacant(entry) => entry.insert(vec!()),
+            };
+
+            let idx = entries.iter().position(|ref entry| {
+                match entry.listener {
+                    EventListenerType::Inline(_) => true,
+                    _ => false,
+                }
+            });
+
+            match idx {
+                Some(idx) => {
+                    match listener {
+                        Some(listener) => entries[idx].listener = EventListenerType::Inline(listener),
+                        None => {
+                            entries.remove(idx);
+                        }
                     }
                 }
-            }
-            None => {
-                if listener.is_some() {
-                    entries.push(EventListenerEntry {
-                        phase: ListenerPhase::Bubbling,
-                        listener: EventListenerType::Inline(listener.unwrap()),
-                    });
+                None => {
+                    if listener.is_some() {
+                        entries.push(EventListenerEntry {
+                            phase: ListenerPhase::Bubbling,
+                            listener: EventListenerType::Inline(listener.unwrap()),
+                        });
+                    }
                 }
             }
         }
+        self.listeners_changed();
     }
 
     fn get_inline_event_listener(self, ty: DOMString) -> Option<Rc<EventListener>> {
@@ -285,6 +298,10 @@ impl<'a> EventTargetHelpers for &'a EventTarget {
     fn has_handlers(self) -> bool {
         !self.handlers.borrow().is_empty()
     }
+
+    fn has_listeners_for(self, type_: &str) -> bool {
+        self.handlers.borrow().get(type_).map_or(false, |entries| !entries.is_empty())
+    }
 }
 
 impl<'a> EventTargetMethods for &'a EventTarget {
@@ -310,8 +327,9 @@ impl<'a> EventTargetMethods for &'a EventTarget {
                     entry.push(new_entry);
                 }
             },
-            _ => (),
+            _ => return,
         }
+        self.listeners_changed();
     }
 
     // https://dom.spec.whatwg.org/#dom-eventtarget-removeeventlistener
@@ -334,8 +352,9 @@ impl<'a> EventTargetMethods for &'a EventTarget {
                     }
                 }
             },
-            _ => (),
+            _ => return,
         }
+        self.listeners_changed();
     }
 
     // https://dom.spec.whatwg.org/#dom-eventtarget-dispatchevent
@@ -2,12 +2,15 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use dom::bindings::cell::DOMRefCell;
+use dom::bindings::codegen::Bindings::BlobBinding::BlobMethods;
 use dom::bindings::codegen::Bindings::WebSocketBinding;
-use dom::bindings::codegen::Bindings::WebSocketBinding::WebSocketMethods;
+use dom::bindings::codegen::Bindings::WebSocketBinding::{BinaryType, WebSocketConstants, WebSocketMethods};
+use dom::bindings::codegen::Bindings::WindowBinding::WindowMethods;
 use dom::bindings::codegen::Bindings::EventHandlerBinding::EventHandlerNonNull;
 use dom::bindings::codegen::InheritTypes::EventTargetCast;
 use dom::bindings::codegen::InheritTypes::EventCast;
+use dom::bindings::conversions::{ConversionBehavior, FromJSValConvertible, StringificationBehavior};
+use dom::bindings::conversions::{ToJSValConvertible, native_from_reflector_jsmanaged};
 use dom::bindings::error::{Error, Fallible};
 use dom::bindings::error::Error::{InvalidAccess, Syntax};
 use dom::bindings::global::{GlobalField, GlobalRef};
@@ -15,151 +18,566 @@ use dom::bindings::js::Root;
 use dom::bindings::refcounted::Trusted;
 use dom::bindings::str::USVString;
 use dom::bindings::trace::JSTraceable;
-use dom::bindings::utils::reflect_dom_object;
+use dom::bindings::utils::{Reflectable, get_dictionary_property, reflect_dom_object};
+use dom::blob::{Blob, BlobHelpers};
 use dom::closeevent::CloseEvent;
+use dom::document::DocumentHelpers;
 use dom::event::{Event, EventBubbles, EventCancelable, EventHelpers};
 use dom::eventtarget::{EventTarget, EventTargetHelpers, EventTargetTypeId};
-use script_task::Runnable;
-use script_task::ScriptMsg;
-use std::cell::{Cell, RefCell};
+use dom::messageevent::MessageEvent;
+use dom::window::WindowHelpers;
+use script_task::{Runnable, ScriptMsg};
+use std::cell::Cell;
 use std::borrow::ToOwned;
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, channel};
+use time;
 use util::str::DOMString;
 use util::task::spawn_named;
 
-use hyper::header::Host;
-use websocket::Message;
-use websocket::ws::sender::Sender as Sender_Object;
-use websocket::client::sender::Sender;
-use websocket::client::receiver::Receiver;
-use websocket::stream::WebSocketStream;
+use js::jsapi::{HandleValue, JSAutoRequest, JSAutoCompartment, JSContext, JSObject, RootedObject, RootedValue};
+use js::jsapi::{JS_GetArrayBufferData, JS_NewArrayBuffer};
+use js::jsapi::{JS_GetObjectAsArrayBuffer, JS_GetObjectAsArrayBufferView};
+use js::jsval::{ObjectValue, UndefinedValue};
+
+use net_traits::{ControlMsg, MessageData};
+use net_traits::{WebSocketCommunicate, WebSocketConnectData, WebSocketDomAction, WebSocketNetworkEvent};
+
+use hyper::header::Headers;
 use websocket::client::request::Url;
-use websocket::Client;
-use websocket::header::Origin;
-use websocket::result::WebSocketResult;
+use websocket::header::WebSocketProtocol;
 use websocket::ws::util::url::parse_url;
 
+use url::{SchemeData, UrlParser};
+
+#[repr(u16)]
 #[derive(JSTraceable, PartialEq, Copy, Clone)]
 enum WebSocketRequestState {
-    Connecting = 0,
-    Open = 1,
-    Closing = 2,
-    Closed = 3,
+    Connecting = WebSocketConstants::CONNECTING,
+    Open = WebSocketConstants::OPEN,
+    Closing = WebSocketConstants::CLOSING,
+    Closed = WebSocketConstants::CLOSED,
 }
 
-no_jsmanaged_fields!(Sender<WebSocketStream>);
+/// The state of a WebSocket that the tasks of its connection update.
+struct ConnectionState {
+    ready_state: WebSocketRequestState,
+    full: bool, //Flag to tell if websocket queue is full
+    clean_close: bool, //Flag to tell if the websocket closed cleanly (not due to full or fail)
+    code: u16, //Closing code
+    reason: DOMString, //Closing reason
+    buffered_amount: u32, //Number of bytes queued by send but not yet transmitted
+    buffered_messages: u32, //Number of messages queued by send but not yet transmitted
+    protocol: DOMString, //Subprotocol selected by the server
+    extensions: DOMString, //Extensions agreed to by the server
+    connection_time_ns: Option<u64>, //Time from construction to the connection being established
+}
+
+/// What a WebSocket shares with the tasks of its connection and with the
+/// window that created it. They hold on to this rather than to the object, so
+/// that an object that can't fire any more events can be collected while its
+/// connection is open, which closes the connection.
+// https://html.spec.whatwg.org/multipage/#garbage-collection-2
+pub struct WebSocketShared {
+    state: Mutex<ConnectionState>,
+    /// Roots the object while it must not be collected. Events are only
+    /// fired at the object through this.
+    pin: Mutex<Option<Trusted<WebSocket>>>,
+    /// Dropped along with the object, which the connection sees as a close.
+    action_sender: Mutex<Option<mpsc::Sender<WebSocketDomAction>>>,
+    /// Whether close() gave up on the connection while it was being
+    /// established.
+    aborted: AtomicBool,
+}
+
+impl WebSocketShared {
+    fn new(action_sender: mpsc::Sender<WebSocketDomAction>) -> WebSocketShared {
+        WebSocketShared {
+            state: Mutex::new(ConnectionState {
+                ready_state: WebSocketRequestState::Connecting,
+                full: false,
+                clean_close: true,
+                code: 0,
+                reason: "".to_owned(),
+                buffered_amount: 0,
+                buffered_messages: 0,
+                protocol: "".to_owned(),
+                extensions: "".to_owned(),
+                connection_time_ns: None,
+            }),
+            pin: Mutex::new(None),
+            action_sender: Mutex::new(Some(action_sender)),
+            aborted: AtomicBool::new(false),
+        }
+    }
+
+    /// The state, which must not be held on to while events are fired, as
+    /// listeners may read it.
+    fn state(&self) -> MutexGuard<ConnectionState> {
+        self.state.lock().unwrap()
+    }
+
+    fn send_action(&self, action: WebSocketDomAction) {
+        if let Some(ref action_sender) = *self.action_sender.lock().unwrap() {
+            let _ = action_sender.send(action);
+        }
+    }
+
+    /// The object, if it is kept alive to have events fired at it.
+    fn root(&self) -> Option<Root<WebSocket>> {
+        self.pin.lock().unwrap().as_ref().map(|pin| pin.root())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state().ready_state == WebSocketRequestState::Closed
+    }
+
+    /// https://html.spec.whatwg.org/multipage/#make-disappear
+    ///
+    /// Called when the document that created the object is unloaded: starts
+    /// the closing handshake with status 1001 (going away) and tells the
+    /// connection to shut down, without firing any further events.
+    pub fn make_disappear(&self) {
+        {
+            let mut state = self.state();
+            match state.ready_state {
+                WebSocketRequestState::Connecting => {
+                    self.aborted.store(true, Ordering::SeqCst);
+                }
+                WebSocketRequestState::Open => {
+                    self.send_action(WebSocketDomAction::Close(Some((1001, "".to_owned()))));
+                }
+                WebSocketRequestState::Closing => {}
+                WebSocketRequestState::Closed => return,
+            }
+            // The document is gone, so the connection is dropped without
+            // waiting for the server.
+            self.send_action(WebSocketDomAction::Abort);
+            // Tasks that are still queued for this object see it closed and do nothing.
+            state.ready_state = WebSocketRequestState::Closed;
+        }
+        *self.pin.lock().unwrap() = None;
+    }
+}
 
 #[dom_struct]
 pub struct WebSocket {
     eventtarget: EventTarget,
     url: Url,
     global: GlobalField,
-    ready_state: Cell<WebSocketRequestState>,
-    sender: RefCell<Option<Sender<WebSocketStream>>>,
-    failed: Cell<bool>, //Flag to tell if websocket was closed due to failure
-    full: Cell<bool>, //Flag to tell if websocket queue is full
-    clean_close: Cell<bool>, //Flag to tell if the websocket closed cleanly (not due to full or fail)
-    code: Cell<u16>, //Closing code
-    reason: DOMRefCell<DOMString>, //Closing reason
-    data: DOMRefCell<DOMString>, //Data from send - TODO: Remove after buffer is added.
+    binary_type: Cell<BinaryType>,
+    shared: Arc<WebSocketShared>,
+}
+
+// https://fetch.spec.whatwg.org/#port-blocking
+const BLOCKED_PORTS_LIST: &'static [u16] = &[
+    1, 7, 9, 11, 13, 15, 17, 19, 20, 21, 22, 23, 25, 37, 42, 43, 53, 77, 79, 87, 95, 101, 102,
+    103, 104, 109, 110, 111, 113, 115, 117, 119, 123, 135, 139, 143, 179, 389, 465, 512, 513,
+    514, 515, 526, 530, 531, 532, 540, 556, 563, 587, 601, 636, 993, 995, 2049, 3659, 4045,
+    6000, 6665, 6666, 6667, 6668, 6669
+];
+
+/// Whether connections to `port` are forbidden by the Fetch spec's bad port list.
+pub fn is_port_blocked(port: u16) -> bool {
+    BLOCKED_PORTS_LIST.iter().any(|&p| p == port)
+}
+
+/// The ASCII serialization of `url`'s origin, or `None` if its origin is
+/// opaque.
+// https://html.spec.whatwg.org/multipage/#ascii-serialisation-of-an-origin
+pub fn ascii_serialize_origin(url: &Url) -> Option<String> {
+    match url.scheme_data {
+        SchemeData::Relative(ref data) if url.scheme != "file" => {
+            let host = data.host.serialize();
+            Some(match data.port {
+                Some(port) => format!("{}://{}:{}", url.scheme, host, port),
+                None => format!("{}://{}", url.scheme, host),
+            })
+        }
+        _ => None,
+    }
 }
 
-/// *Establish a WebSocket Connection* as defined in RFC 6455.
-fn establish_a_websocket_connection(url: (Host, String, bool), origin: String)
-    -> WebSocketResult<(Sender<WebSocketStream>, Receiver<WebSocketStream>)> {
-    let mut request = try!(Client::connect(url));
-    request.headers.set(Origin(origin));
+/// Parses the URL passed to the WebSocket constructor against `base`,
+/// failing if it isn't a valid WebSocket URL. A URL resolving to http: or
+/// https: is upgraded to ws: or wss: respectively.
+// https://html.spec.whatwg.org/multipage/#dom-websocket
+pub fn parse_web_socket_url(base: &Url, url: &str) -> Result<Url, ()> {
+    let mut parsed_url = try!(UrlParser::new().base_url(base).parse(url).map_err(|_| ()));
+    // The default ports of http: and https: are those of ws: and wss:, so
+    // only the scheme needs replacing.
+    let scheme = match &*parsed_url.scheme {
+        "http" => Some("ws"),
+        "https" => Some("wss"),
+        _ => None,
+    };
+    if let Some(scheme) = scheme {
+        parsed_url.scheme = scheme.to_owned();
+    }
+    if parsed_url.scheme != "ws" && parsed_url.scheme != "wss" {
+        return Err(());
+    }
+    if parsed_url.fragment.is_some() {
+        return Err(());
+    }
+    Ok(parsed_url)
+}
+
+/// Whether a WebSocket connection from a document at `base` is an insecure
+/// connection from a secure document, which must not be made: a ws: URL from
+/// an https: document.
+pub fn is_insecure_connection_from_secure_document(base: &Url, secure: bool) -> bool {
+    !secure && base.scheme == "https"
+}
+
+/// Status code sent by `close()` when script doesn't give one.
+pub const NORMAL_CLOSURE: u16 = 1000;
+
+/// Status code reported when the connection closed without a close frame.
+pub const ABNORMAL_CLOSURE: u16 = 1006;
 
-    let response = try!(request.send());
-    try!(response.validate());
+/// The longest close reason, in bytes, that fits in a control frame along
+/// with the status code.
+pub const MAX_CLOSE_REASON_LENGTH: usize = 123;
 
-    Ok(response.begin().split())
+/// Whether `reason` can be sent in a close frame. The limit applies to its
+/// UTF-8 encoding; a `USVString` has had any lone surrogates replaced by
+/// U+FFFD by the time it gets here.
+pub fn is_valid_close_reason(reason: &str) -> bool {
+    reason.len() <= MAX_CLOSE_REASON_LENGTH
 }
 
+/// Whether script may close a connection with `code`: 1000, or one of the
+/// codes in 3000-4999, which are left to libraries and applications. The
+/// other codes are reserved for the protocol.
+// https://html.spec.whatwg.org/multipage/#dom-websocket-close
+pub fn is_valid_close_code(code: u16) -> bool {
+    code == NORMAL_CLOSURE || (code >= 3000 && code <= 4999)
+}
+
+/// The status code and reason of the close frame started by
+/// `close(code, reason)`.
+pub fn close_frame_data(code: Option<u16>, reason: Option<String>) -> (u16, String) {
+    (code.unwrap_or(NORMAL_CLOSURE), reason.unwrap_or("".to_owned()))
+}
+
+/// The largest `bufferedAmount` a WebSocket accepts before it is considered
+/// full.
+pub const MAX_BUFFERED_AMOUNT: u32 = 16 * 1024 * 1024;
+
+/// Whether queueing another `data_byte_len` bytes on top of
+/// `buffered_amount` would overflow the send buffer.
+pub fn send_buffer_is_full(buffered_amount: u32, data_byte_len: u32) -> bool {
+    buffered_amount.checked_add(data_byte_len).map_or(true, |total| total > MAX_BUFFERED_AMOUNT)
+}
+
+/// The most messages a WebSocket queues before it is considered full, so
+/// that many tiny messages can't grow the queue without bound either.
+pub const MAX_BUFFERED_MESSAGES: u32 = 64 * 1024;
+
+/// Whether queueing another message on top of `buffered_messages` would
+/// overflow the send queue.
+pub fn send_queue_is_full(buffered_messages: u32) -> bool {
+    buffered_messages >= MAX_BUFFERED_MESSAGES
+}
+
+/// The value of the `extensions` attribute for the raw lines of the
+/// `Sec-WebSocket-Extensions` response header: the extensions in use, with
+/// their parameters, as the server sent them.
+pub fn extensions_value(header_lines: &[Vec<u8>]) -> String {
+    let lines: Vec<String> = header_lines.iter().map(|line| String::from_utf8_lossy(line).into_owned()).collect();
+    lines.connect(", ")
+}
+
+/// Whether `c` may appear in an HTTP token: a visible ASCII character
+/// other than a separator.
+// https://tools.ietf.org/html/rfc2616#section-2.2
+fn is_token_char(c: char) -> bool {
+    c >= '\u{0021}' && c <= '\u{007E}' && !"()<>@,;:\\\"/[]?={}".contains(c)
+}
+
+/// Whether `protocols` is a list of subprotocols that may be offered in a
+/// handshake: each one must be a non-empty token, and none may repeat.
+/// https://tools.ietf.org/html/rfc6455#section-4.1, handshake requirements, step 10
+pub fn protocols_are_valid(protocols: &[DOMString]) -> bool {
+    protocols.iter().enumerate().all(|(i, protocol)| {
+        !protocol.is_empty() &&
+        protocol.chars().all(is_token_char) &&
+        !protocols[i+1..].iter().any(|p| p == protocol)
+    })
+}
+
+/// Converts the `protocols` argument of the constructor, a string or a
+/// sequence of strings, to the list of protocols to offer. A missing argument
+/// is an empty list, and a single string a list of one.
+// https://heycam.github.io/webidl/#es-union
+// https://heycam.github.io/webidl/#es-sequence
+#[allow(unsafe_code)]
+fn protocols_from_jsval(cx: *mut JSContext, protocols: HandleValue) -> Fallible<Vec<DOMString>> {
+    if protocols.get().is_undefined() {
+        return Ok(vec![]);
+    }
+    if !protocols.get().is_object() {
+        let protocol = try!(DOMString::from_jsval(cx, protocols, StringificationBehavior::Default)
+                                .map_err(|_| Error::JSFailed));
+        return Ok(vec![protocol]);
+    }
+
+    let sequence = RootedObject::new(cx, protocols.get().to_object());
+    let mut value = RootedValue::new(cx, UndefinedValue());
+    try!(get_dictionary_property(cx, sequence.handle(), "length", value.handle_mut())
+             .map_err(|_| Error::JSFailed));
+    let length = try!(u32::from_jsval(cx, value.handle(), ConversionBehavior::Default)
+                          .map_err(|_| Error::JSFailed));
+    let mut list = vec![];
+    for index in 0..length {
+        value.ptr = UndefinedValue();
+        try!(get_dictionary_property(cx, sequence.handle(), &index.to_string(), value.handle_mut())
+                 .map_err(|_| Error::JSFailed));
+        list.push(try!(DOMString::from_jsval(cx, value.handle(), StringificationBehavior::Default)
+                           .map_err(|_| Error::JSFailed)));
+    }
+    Ok(list)
+}
 
 impl WebSocket {
-    fn new_inherited(global: GlobalRef, url: Url) -> WebSocket {
+    fn new_inherited(global: GlobalRef, url: Url, shared: Arc<WebSocketShared>) -> WebSocket {
         WebSocket {
             eventtarget: EventTarget::new_inherited(EventTargetTypeId::WebSocket),
             url: url,
             global: GlobalField::from_rooted(&global),
-            ready_state: Cell::new(WebSocketRequestState::Connecting),
-            failed: Cell::new(false),
-            sender: RefCell::new(None),
-            full: Cell::new(false),
-            clean_close: Cell::new(true),
-            code: Cell::new(0),
-            reason: DOMRefCell::new("".to_owned()),
-            data: DOMRefCell::new("".to_owned()),
+            binary_type: Cell::new(BinaryType::Blob),
+            shared: shared,
+        }
+
+    }
+
+    /// Shared steps of the `send()` overloads: checks the ready state and
+    /// accounts for `data_byte_len` in `bufferedAmount`, closing the
+    /// connection if the send buffer or queue is full. Returns whether the data should
+    /// actually be transmitted.
+    fn send_impl(&self, data_byte_len: u32) -> Fallible<bool> {
+        let transmit = {
+            let mut state = self.shared.state();
+            let full = send_buffer_is_full(state.buffered_amount, data_byte_len) ||
+                       send_queue_is_full(state.buffered_messages);
+            let ready_state = state.ready_state;
+            match ready_state {
+                WebSocketRequestState::Connecting => {
+                    return Err(Error::InvalidState);
+                },
+                WebSocketRequestState::Open if full => {
+                    // The data can't be buffered: flag the WebSocket as full
+                    // and close the connection, which fires error and close
+                    // events.
+                    state.full = true;
+                    state.ready_state = WebSocketRequestState::Closing;
+                    self.shared.send_action(WebSocketDomAction::Abort);
+                    false
+                },
+                WebSocketRequestState::Open => {
+                    state.buffered_amount = state.buffered_amount.saturating_add(data_byte_len);
+                    state.buffered_messages += 1;
+                    true
+                },
+                WebSocketRequestState::Closing | WebSocketRequestState::Closed => {
+                    state.buffered_amount = state.buffered_amount.saturating_add(data_byte_len);
+                    false
+                },
+            }
+        };
+        self.update_pin();
+        Ok(transmit)
+    }
+
+    /// The `send(Blob)` overload. `bufferedAmount` accounts for the blob's size
+    /// immediately, while its data is only copied on the connection's thread,
+    /// so that a large blob doesn't hold up the script task.
+    fn send_blob(&self, blob: &Blob) -> Fallible<()> {
+        if !try!(self.send_impl(blob.Size() as u32)) {
+            return Ok(());
+        }
+
+        self.shared.send_action(WebSocketDomAction::SendBlob(blob.shared_bytes()));
+        Ok(())
+    }
+
+    /// Queues `message` for the connection, whose bytes are removed from
+    /// `bufferedAmount` once it has been written.
+    fn transmit(&self, message: MessageData) {
+        self.shared.send_action(WebSocketDomAction::SendMessage(message));
+    }
+
+    /// Roots this object while it must be kept alive, and unroots it once it
+    /// may be collected. Called whenever its ready state, bufferedAmount or
+    /// listeners change.
+    // https://html.spec.whatwg.org/multipage/#garbage-collection-2
+    pub fn update_pin(&self) {
+        let target = EventTargetCast::from_ref(self);
+        let has_listeners = |types: &[&str]| types.iter().any(|type_| target.has_listeners_for(type_));
+        let keep_alive = {
+            let state = self.shared.state();
+            match state.ready_state {
+                WebSocketRequestState::Connecting => has_listeners(&["open", "message", "error", "close"]),
+                // A connection with data queued to be transmitted must not be
+                // dropped either.
+                WebSocketRequestState::Open => {
+                    has_listeners(&["message", "error", "close"]) || state.buffered_amount > 0
+                }
+                WebSocketRequestState::Closing => {
+                    has_listeners(&["error", "close"]) || state.buffered_amount > 0
+                }
+                WebSocketRequestState::Closed => false,
+            }
+        };
+        let mut pin = self.shared.pin.lock().unwrap();
+        if !keep_alive {
+            *pin = None;
+        } else if pin.is_none() {
+            let global = self.global.root();
+            *pin = Some(Trusted::new(global.r().get_cx(), self, global.r().script_chan()));
         }
+    }
 
+    /// How long the connection took to be established after this object was
+    /// created, once it is open. Not exposed to script; this is for catching
+    /// regressions in the handshake.
+    pub fn connection_time_ns(&self) -> Option<u64> {
+        self.shared.state().connection_time_ns
     }
 
-    fn new(global: GlobalRef, url: Url) -> Root<WebSocket> {
-        reflect_dom_object(box WebSocket::new_inherited(global, url),
+    fn new(global: GlobalRef, url: Url, shared: Arc<WebSocketShared>) -> Root<WebSocket> {
+        reflect_dom_object(box WebSocket::new_inherited(global, url, shared),
                            global, WebSocketBinding::Wrap)
     }
 
-    pub fn Constructor(global: GlobalRef,
+    pub fn Constructor(cx: *mut JSContext,
+                       global: GlobalRef,
                        url: DOMString,
-                       protocols: Option<DOMString>)
+                       protocols: HandleValue)
                        -> Fallible<Root<WebSocket>> {
-        // Step 1.
-        let parsed_url = try!(Url::parse(&url).map_err(|_| Error::Syntax));
+        // Step 1: in a document, the URL is resolved against its base URL,
+        // which a <base href> may change.
+        let base_url = match global {
+            GlobalRef::Window(window) => window.Document().r().base_url(),
+            GlobalRef::Worker(_) => global.get_url(),
+        };
+        let parsed_url = try!(parse_web_socket_url(&base_url, &url).map_err(|_| Error::Syntax));
         let url = try!(parse_url(&parsed_url).map_err(|_| Error::Syntax));
 
         // Step 2: Disallow https -> ws connections.
-        // Step 3: Potentially block access to some ports.
+        let (_, _, secure) = url;
+        if is_insecure_connection_from_secure_document(&global.get_url(), secure) {
+            return Err(Error::Security);
+        }
 
-        // Step 4.
-        let protocols = protocols.as_slice();
+        // Step 3: Potentially block access to some ports.
+        // `parse_url` fills in the scheme's default port when none is given.
+        if url.0.port.map_or(false, is_port_blocked) {
+            return Err(Error::Security);
+        }
 
-        // Step 5.
-        for (i, protocol) in protocols.iter().enumerate() {
-            // https://tools.ietf.org/html/rfc6455#section-4.1
-            // Handshake requirements, step 10
-            if protocol.is_empty() {
-                return Err(Syntax);
-            }
+        // FIXME: Throw a SecurityError if the Content Security Policy doesn't
+        // allow connecting to the URL (connect-src). Servo doesn't parse or
+        // enforce CSP yet.
 
-            if protocols[i+1..].iter().any(|p| p == protocol) {
-                return Err(Syntax);
-            }
+        // Step 4: a single protocol is treated as a list of one.
+        let protocols = try!(protocols_from_jsval(cx, protocols));
 
-            if protocol.chars().any(|c| c < '\u{0021}' || c > '\u{007E}') {
-                return Err(Syntax);
-            }
+        // Step 5.
+        if !protocols_are_valid(&protocols) {
+            return Err(Syntax);
         }
 
-        // Step 6: Origin.
+        // Step 6: Origin. In a worker, `get_url` is the worker script's URL,
+        // which is same-origin with the document that started the worker.
+        let origin = ascii_serialize_origin(&global.get_url());
 
         // Step 7.
-        let ws = WebSocket::new(global, parsed_url);
-        let address = Trusted::new(global.get_cx(), ws.r(), global.script_chan());
+        let connect_start_ns = time::precise_time_ns();
+        let (event_sender, event_receiver) = channel();
+        let (action_sender, action_receiver) = channel();
+        let connect = WebSocketCommunicate {
+            event_sender: event_sender,
+            action_receiver: action_receiver,
+        };
+        let connect_data = WebSocketConnectData {
+            resource_url: parsed_url.clone(),
+            origin: origin,
+            protocols: protocols,
+            mem_profiler_chan: global.mem_profiler_chan(),
+        };
+        let _ = global.resource_task().send(ControlMsg::WebsocketConnect(connect, connect_data));
+
+        let shared = Arc::new(WebSocketShared::new(action_sender));
+        let ws = WebSocket::new(global, parsed_url, shared.clone());
+        if let GlobalRef::Window(window) = global {
+            window.register_websocket(shared.clone());
+        }
 
-        let origin = global.get_url().serialize();
+        // Step 8: Protocols.
+        // Step 9: the connection is established by the resource task, which
+        // reports back here.
+        // The resource task reports the events of a connection in order on a
+        // single channel, and they are queued here as tasks on a single script
+        // channel, so the open event always precedes any message event, and a
+        // message received before the server closed the connection is
+        // dispatched before the close event.
         let sender = global.script_chan();
-        spawn_named(format!("WebSocket connection to {}", ws.Url()), move || {
-            // Step 8: Protocols.
-
-            // Step 9.
-            let channel = establish_a_websocket_connection(url, origin);
-            let (temp_sender, _temp_receiver) = match channel {
-                Ok(channel) => channel,
-                Err(e) => {
-                    debug!("Failed to establish a WebSocket connection: {:?}", e);
-                    let task = box CloseTask {
-                        addr: address,
-                    };
-                    sender.send(ScriptMsg::RunnableMsg(task)).unwrap();
+        spawn_named(format!("WebSocket events for {}", ws.Url()), move || {
+            for event in event_receiver.iter() {
+                // Whether this is the last event of the connection.
+                let mut closed = false;
+                let task: Box<Runnable + Send> = match event {
+                    WebSocketNetworkEvent::ConnectionEstablished(headers) => {
+                        box ConnectionEstablishedTask {
+                            shared: shared.clone(),
+                            headers: headers,
+                            connection_time_ns: time::precise_time_ns().saturating_sub(connect_start_ns),
+                        }
+                    }
+                    WebSocketNetworkEvent::MessageReceived(message) => {
+                        box MessageReceivedTask {
+                            shared: shared.clone(),
+                            message: message,
+                        }
+                    }
+                    WebSocketNetworkEvent::MessageSent(amount) => {
+                        box BufferedAmountTask {
+                            shared: shared.clone(),
+                            amount: amount,
+                        }
+                    }
+                    WebSocketNetworkEvent::Close(code, reason) => {
+                        closed = true;
+                        box CloseTask {
+                            shared: shared.clone(),
+                            kind: CloseKind::Clean,
+                            code: Some(code),
+                            reason: Some(reason),
+                        }
+                    }
+                    WebSocketNetworkEvent::Fail => {
+                        closed = true;
+                        // A connection that dropped without a close frame
+                        // reports 1006.
+                        box CloseTask {
+                            shared: shared.clone(),
+                            kind: CloseKind::of_failure(shared.aborted.load(Ordering::SeqCst)),
+                            code: Some(ABNORMAL_CLOSURE),
+                            reason: None,
+                        }
+                    }
+                };
+                // The script task may already be gone, in which case there
+                // is nobody left to deliver events to.
+                if sender.send(ScriptMsg::RunnableMsg(task)).is_err() || closed {
                     return;
                 }
-            };
-
-            let open_task = box ConnectionEstablishedTask {
-                addr: address,
-                sender: temp_sender,
-            };
-            sender.send(ScriptMsg::RunnableMsg(open_task)).unwrap();
+            }
         });
 
         // Step 7.
@@ -167,97 +585,152 @@ impl WebSocket {
     }
 }
 
+impl Drop for WebSocket {
+    /// Once the object is collected, nothing can observe its connection any
+    /// more. Dropping the sender of its actions lets the connection close.
+    fn drop(&mut self) {
+        self.shared.action_sender.lock().unwrap().take();
+    }
+}
+
 impl<'a> WebSocketMethods for &'a WebSocket {
     event_handler!(open, GetOnopen, SetOnopen);
     event_handler!(close, GetOnclose, SetOnclose);
     event_handler!(error, GetOnerror, SetOnerror);
+    event_handler!(message, GetOnmessage, SetOnmessage);
 
     // https://html.spec.whatwg.org/multipage/#dom-websocket-url
     fn Url(self) -> DOMString {
+        // The URL parser drops a port that is the scheme's default, so it
+        // is never serialized.
         self.url.serialize()
     }
 
     // https://html.spec.whatwg.org/multipage/#dom-websocket-readystate
     fn ReadyState(self) -> u16 {
-        self.ready_state.get() as u16
+        self.shared.state().ready_state as u16
+    }
+
+    // https://html.spec.whatwg.org/multipage/#dom-websocket-extensions
+    fn Extensions(self) -> DOMString {
+        self.shared.state().extensions.clone()
+    }
+
+    // https://html.spec.whatwg.org/multipage/#dom-websocket-protocol
+    fn Protocol(self) -> DOMString {
+        self.shared.state().protocol.clone()
+    }
+
+    // https://html.spec.whatwg.org/multipage/#dom-websocket-bufferedamount
+    fn BufferedAmount(self) -> u32 {
+        self.shared.state().buffered_amount
+    }
+
+    // https://html.spec.whatwg.org/multipage/#dom-websocket-binarytype
+    fn BinaryType(self) -> BinaryType {
+        self.binary_type.get()
+    }
+
+    // https://html.spec.whatwg.org/multipage/#dom-websocket-binarytype
+    fn SetBinaryType(self, btype: BinaryType) {
+        self.binary_type.set(btype)
     }
 
     // https://html.spec.whatwg.org/multipage/#dom-websocket-send
-    fn Send(self, data: Option<USVString>) -> Fallible<()> {
-        match self.ready_state.get() {
-            WebSocketRequestState::Connecting => {
-                return Err(Error::InvalidState);
-            },
-            WebSocketRequestState::Open => (),
-            WebSocketRequestState::Closing | WebSocketRequestState::Closed => {
-                // TODO: Update bufferedAmount.
-                return Ok(());
-            }
+    fn Send(self, data: USVString) -> Fallible<()> {
+        let data_byte_len = data.0.as_bytes().len() as u32;
+        if !try!(self.send_impl(data_byte_len)) {
+            return Ok(());
         }
 
-        /*TODO: This is not up to spec see http://html.spec.whatwg.org/multipage/comms.html search for
-                "If argument is a string"
-          TODO: Need to buffer data
-          TODO: bufferedAmount attribute returns the size of the buffer in bytes -
-                this is a required attribute defined in the websocket.webidl file
-          TODO: The send function needs to flag when full by using the following
-          self.full.set(true). This needs to be done when the buffer is full
-        */
-        let mut other_sender = self.sender.borrow_mut();
-        let my_sender = other_sender.as_mut().unwrap();
-        let _ = my_sender.send_message(Message::Text(data.unwrap().0));
+        self.transmit(MessageData::Text(data.0));
         return Ok(())
     }
 
+    #[allow(unsafe_code)]
+    // https://html.spec.whatwg.org/multipage/#dom-websocket-send
+    fn Send_(self, _cx: *mut JSContext, data: *mut JSObject) -> Fallible<()> {
+        // Blob and object aren't distinguishable overloads, so blobs come through here too.
+        if let Ok(blob) = native_from_reflector_jsmanaged::<Blob>(data) {
+            return self.send_blob(blob.r());
+        }
+
+        let mut length = 0;
+        let mut buffer = ptr::null_mut();
+        // For a view, this yields only the `byteLength` bytes starting at its
+        // `byteOffset`, not the whole underlying buffer.
+        let is_buffer_source = unsafe {
+            !JS_GetObjectAsArrayBuffer(data, &mut length, &mut buffer).is_null() ||
+            !JS_GetObjectAsArrayBufferView(data, &mut length, &mut buffer).is_null()
+        };
+        if !is_buffer_source {
+            return Err(Error::Type(
+                "Argument to WebSocket.send is not a Blob, ArrayBuffer or ArrayBufferView".to_owned()));
+        }
+
+        if !try!(self.send_impl(length)) {
+            return Ok(());
+        }
+
+        let bytes = unsafe {
+            slice::from_raw_parts(buffer as *const u8, length as usize)
+        };
+        self.transmit(MessageData::Binary(bytes.to_vec()));
+        Ok(())
+    }
+
     // https://html.spec.whatwg.org/multipage/#dom-websocket-close
     fn Close(self, code: Option<u16>, reason: Option<USVString>) -> Fallible<()>{
-        fn send_close(this: &WebSocket) {
-            this.ready_state.set(WebSocketRequestState::Closing);
-
-            let mut sender = this.sender.borrow_mut();
-            //TODO: Also check if the buffer is full
-            if let Some(sender) = sender.as_mut() {
-                let _ = sender.send_message(Message::Close(None));
-            }
+        fn send_close(this: &WebSocket, action: WebSocketDomAction) {
+            this.shared.state().ready_state = WebSocketRequestState::Closing;
+            // The close frame is queued behind the messages already sent; the
+            // limit on buffered data only applies to send().
+            this.shared.send_action(action);
         }
 
 
         if let Some(code) = code {
-            //Check code is NOT 1000 NOR in the range of 3000-4999 (inclusive)
-            if  code != 1000 && (code < 3000 || code > 4999) {
+            if !is_valid_close_code(code) {
                 return Err(Error::InvalidAccess);
             }
         }
         if let Some(ref reason) = reason {
-            if reason.0.as_bytes().len() > 123 { //reason cannot be larger than 123 bytes
+            if !is_valid_close_reason(&reason.0) {
                 return Err(Error::Syntax);
             }
         }
 
-        match self.ready_state.get() {
+        let ready_state = self.shared.state().ready_state;
+        match ready_state {
             WebSocketRequestState::Closing | WebSocketRequestState::Closed  => {} //Do nothing
             WebSocketRequestState::Connecting => { //Connection is not yet established
                 /*By setting the state to closing, the open function
                   will abort connecting the websocket*/
-                self.failed.set(true);
-                send_close(self);
+                self.shared.aborted.store(true, Ordering::SeqCst);
+                send_close(self, WebSocketDomAction::Abort);
                 //Note: After sending the close message, the receive loop confirms a close message from the server and
                 //      must fire a close event
             }
             WebSocketRequestState::Open => {
                 //Closing handshake not started - still in open
                 //Start the closing by setting the code and reason if they exist
-                if let Some(code) = code {
-                    self.code.set(code);
+                {
+                    let mut state = self.shared.state();
+                    if let Some(code) = code {
+                        state.code = code;
+                    }
+                    if let Some(ref reason) = reason {
+                        state.reason = reason.0.clone();
+                    }
                 }
-                if let Some(reason) = reason {
-                    *self.reason.borrow_mut() = reason.0;
-                }
-                send_close(self);
+                // The reason was checked to fit in a control frame above.
+                let data = close_frame_data(code, reason.map(|reason| reason.0));
+                send_close(self, WebSocketDomAction::Close(Some(data)));
                 //Note: After sending the close message, the receive loop confirms a close message from the server and
                 //      must fire a close event
             }
         }
+        self.update_pin();
         Ok(()) //Return Ok
     }
 }
@@ -265,59 +738,173 @@ impl<'a> WebSocketMethods for &'a WebSocket {
 
 /// Task queued when *the WebSocket connection is established*.
 struct ConnectionEstablishedTask {
-    addr: Trusted<WebSocket>,
-    sender: Sender<WebSocketStream>,
+    shared: Arc<WebSocketShared>,
+    headers: Headers,
+    /// The time from the constructor to the connection being established.
+    connection_time_ns: u64,
 }
 
 impl Runnable for ConnectionEstablishedTask {
     fn handler(self: Box<Self>) {
-        let ws = self.addr.root();
+        {
+            let mut state = self.shared.state();
 
-        *ws.r().sender.borrow_mut() = Some(self.sender);
+            // close() may have been called while this task was queued, in which
+            // case the connection is being dropped.
+            if state.ready_state != WebSocketRequestState::Connecting {
+                return;
+            }
 
-        // Step 1: Protocols.
+            // Step 1: Protocols.
 
-        // Step 2.
-        ws.ready_state.set(WebSocketRequestState::Open);
+            // Step 2.
+            state.ready_state = WebSocketRequestState::Open;
+            state.connection_time_ns = Some(self.connection_time_ns);
+            debug!("WebSocket connection established in {} ms", self.connection_time_ns / 1_000_000);
 
-        // Step 3: Extensions.
-        // Step 4: Protocols.
-        // Step 5: Cookies.
+            // Step 3.
+            if let Some(extensions_in_use) = self.headers.get_raw("Sec-WebSocket-Extensions") {
+                state.extensions = extensions_value(extensions_in_use);
+            }
+
+            // Step 4. The server may select none of the offered protocols, in
+            // which case protocol stays the empty string.
+            if let Some(&WebSocketProtocol(ref protocol_in_use)) = self.headers.get() {
+                if let Some(protocol) = protocol_in_use.first() {
+                    state.protocol = protocol.clone();
+                }
+            }
+        }
+
+        // Step 5: Cookies are stored by the resource task.
 
         // Step 6.
+        let ws = match self.shared.root() {
+            Some(ws) => ws,
+            None => return,
+        };
         let global = ws.global.root();
         let event = Event::new(global.r(), "open".to_owned(),
                                EventBubbles::DoesNotBubble,
                                EventCancelable::NotCancelable);
         event.fire(EventTargetCast::from_ref(ws.r()));
+        ws.update_pin();
+    }
+}
+
+/// Task queued by the connection thread once sent data has been handed to the
+/// network.
+///
+/// Per spec, `bufferedAmount` only decreases when the event loop gets to run
+/// this task, not while script is still running.
+struct BufferedAmountTask {
+    shared: Arc<WebSocketShared>,
+    amount: u32,
+}
+
+impl Runnable for BufferedAmountTask {
+    fn handler(self: Box<Self>) {
+        {
+            let mut state = self.shared.state();
+            // Once the connection is closed, bufferedAmount keeps the bytes that
+            // were still buffered, and only grows with further calls to send().
+            if state.ready_state == WebSocketRequestState::Closed {
+                return;
+            }
+            state.buffered_amount = state.buffered_amount.saturating_sub(self.amount);
+            state.buffered_messages = state.buffered_messages.saturating_sub(1);
+        }
+        // The object may be collected once nothing is left to transmit.
+        if let Some(ws) = self.shared.root() {
+            ws.update_pin();
+        }
+    }
+}
+
+/// How a WebSocket connection came to be closed.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CloseKind {
+    /// The closing handshake completed.
+    Clean,
+    /// The connection was failed, for instance because the server refused the
+    /// handshake or sent an invalid frame.
+    Failed,
+    /// close() was called before the connection was established.
+    Aborted,
+}
+
+impl CloseKind {
+    /// How a connection that the network reported as failed came to be
+    /// closed: a connection that close() already gave up on is aborted, not
+    /// failed.
+    pub fn of_failure(aborted: bool) -> CloseKind {
+        if aborted {
+            CloseKind::Aborted
+        } else {
+            CloseKind::Failed
+        }
+    }
+
+    /// Whether an error event is fired before the close event. A connection
+    /// that close() gave up on was closed by the user rather than by the
+    /// network, so it only gets a close event.
+    pub fn fires_error(self, send_buffer_full: bool) -> bool {
+        self == CloseKind::Failed || send_buffer_full
+    }
+
+    /// Whether the close event reports that the connection closed cleanly.
+    pub fn was_clean(self, send_buffer_full: bool) -> bool {
+        self == CloseKind::Clean && !send_buffer_full
     }
 }
 
 struct CloseTask {
-    addr: Trusted<WebSocket>,
+    shared: Arc<WebSocketShared>,
+    kind: CloseKind,
+    /// The status code of the server's close frame, if any.
+    code: Option<u16>,
+    /// The reason of the server's close frame, if any.
+    reason: Option<String>,
 }
 
 impl Runnable for CloseTask {
     fn handler(self: Box<Self>) {
-        let ws = self.addr.root();
+        let (full, clean_close, code, reason) = {
+            let mut state = self.shared.state();
+            if state.ready_state == WebSocketRequestState::Closed {
+                // The object was made to disappear with its document.
+                return;
+            }
+            state.ready_state = WebSocketRequestState::Closed;
+            // The code and reason the server closed with are reported, even if
+            // they differ from those close() sent.
+            if let Some(code) = self.code {
+                state.code = code;
+            }
+            if let Some(reason) = self.reason {
+                state.reason = reason;
+            }
+            let full = state.full;
+            state.full = false;
+            state.clean_close = self.kind.was_clean(full);
+            (full, state.clean_close, state.code, state.reason.clone())
+        };
+        let ws = match self.shared.root() {
+            Some(ws) => ws,
+            None => return,
+        };
         let ws = ws.r();
         let global = ws.global.root();
-        ws.ready_state.set(WebSocketRequestState::Closed);
-        //If failed or full, fire error event
-        if ws.failed.get() || ws.full.get() {
-            ws.failed.set(false);
-            ws.full.set(false);
-            //A Bad close
-            ws.clean_close.set(false);
-            let event = Event::new(global.r(),
-                                   "error".to_owned(),
+        // The object stays rooted by this task until the events below have
+        // been dispatched.
+        ws.update_pin();
+        if self.kind.fires_error(full) {
+            let event = Event::new(global.r(), "error".to_owned(),
                                    EventBubbles::DoesNotBubble,
-                                   EventCancelable::Cancelable);
+                                   EventCancelable::NotCancelable);
             let target = EventTargetCast::from_ref(ws);
             event.r().fire(target);
         }
-        let rsn = ws.reason.borrow();
-        let rsn_clone = rsn.clone();
         /*In addition, we also have to fire a close even if error event fired
          https://html.spec.whatwg.org/multipage/#closeWebSocket
         */
@@ -325,11 +912,66 @@ impl Runnable for CloseTask {
                                           "close".to_owned(),
                                           EventBubbles::DoesNotBubble,
                                           EventCancelable::NotCancelable,
-                                          ws.clean_close.get(),
-                                          ws.code.get(),
-                                          rsn_clone);
+                                          clean_close,
+                                          code,
+                                          reason);
         let target = EventTargetCast::from_ref(ws);
         let event = EventCast::from_ref(close_event.r());
         event.fire(target);
     }
 }
+
+/// Task queued when *a WebSocket message has been received*.
+struct MessageReceivedTask {
+    shared: Arc<WebSocketShared>,
+    message: MessageData,
+}
+
+impl Runnable for MessageReceivedTask {
+    #[allow(unsafe_code)]
+    fn handler(self: Box<Self>) {
+        // Step 1.
+        if self.shared.state().ready_state != WebSocketRequestState::Open {
+            return;
+        }
+        let ws = match self.shared.root() {
+            Some(ws) => ws,
+            None => return,
+        };
+        let ws = ws.r();
+
+        // Step 2-5.
+        let global = ws.global.root();
+        let cx = global.r().get_cx();
+        let target = EventTargetCast::from_ref(ws);
+        let _ar = JSAutoRequest::new(cx);
+        let _ac = JSAutoCompartment::new(cx, target.reflector().get_jsobject().get());
+        let mut message = RootedValue::new(cx, UndefinedValue());
+        // The type of binary data depends on the value of binaryType at the
+        // time the message is dispatched.
+        match (self.message, ws.binary_type.get()) {
+            (MessageData::Text(text), _) => text.to_jsval(cx, message.handle_mut()),
+            (MessageData::Binary(data), BinaryType::Blob) => {
+                let blob = Blob::new(global.r(), Some(data), "");
+                blob.to_jsval(cx, message.handle_mut());
+            }
+            (MessageData::Binary(data), BinaryType::Arraybuffer) => unsafe {
+                // The buffer is exactly as long as the message.
+                let length = data.len() as u32;
+                let buffer = JS_NewArrayBuffer(cx, length);
+                let buffer_data = JS_GetArrayBufferData(buffer, ptr::null());
+                ptr::copy_nonoverlapping(data.as_ptr(), buffer_data, length as usize);
+                message.ptr = ObjectValue(&*buffer);
+            },
+        }
+        // The event's origin is that of the WebSocket URL, which is always
+        // a tuple origin.
+        let origin = ascii_serialize_origin(&ws.url).unwrap_or("null".to_owned());
+        // Unlike postMessage(), a WebSocket message has no last event ID,
+        // and no source or ports; MessageEvent doesn't expose the latter two
+        // yet, so they would be null and empty.
+        let event = MessageEvent::new(global.r(), "message".to_owned(), false, false,
+                                      message.handle(), origin, "".to_owned());
+        EventCast::from_ref(event.r()).fire(target);
+    }
+}
@@ -31,6 +31,7 @@ use dom::node::{window_from_node, TrustedNodeAddress, NodeHelpers};
 use dom::performance::Performance;
 use dom::screen::Screen;
 use dom::storage::Storage;
+use dom::websocket::WebSocketShared;
 use layout_interface::{ReflowGoal, ReflowQueryType, LayoutRPC, LayoutChan, Reflow, Msg};
 use layout_interface::{ContentBoxResponse, ContentBoxesResponse, ResolvedStyleResponse, ScriptReflow};
 use page::Page;
@@ -74,6 +75,7 @@ use std::default::Default;
 use std::ffi::CString;
 use std::mem as std_mem;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::sync::mpsc::{channel, Receiver};
 use std::sync::mpsc::TryRecvError::{Empty, Disconnected};
 use time;
@@ -196,6 +198,10 @@ pub struct Window {
 
     /// The current state of the window object
     current_state: Cell<WindowState>,
+
+    /// The WebSocket objects created by this window that may not have been closed yet. They
+    /// are referred to by their shared state so that they can still be garbage collected.
+    websockets: DOMRefCell<Vec<Arc<WebSocketShared>>>,
 }
 
 impl Window {
@@ -565,6 +571,7 @@ impl<'a> WindowMethods for &'a Window {
 
 pub trait WindowHelpers {
     fn clear_js_runtime(self);
+    fn register_websocket(self, ws: Arc<WebSocketShared>);
     fn init_browsing_context(self, doc: &Document, frame_element: Option<&Element>);
     fn load_url(self, href: DOMString);
     fn handle_fire_timer(self, timer_id: TimerId);
@@ -650,6 +657,13 @@ impl<'a, T: Reflectable> ScriptHelpers for &'a T {
 
 impl<'a> WindowHelpers for &'a Window {
     fn clear_js_runtime(self) {
+        // https://html.spec.whatwg.org/multipage/#unloading-document-cleanup-steps
+        // Step 4: make disappear any WebSocket objects that were created by this window.
+        let websockets = std_mem::replace(&mut *self.websockets.borrow_mut(), vec!());
+        for ws in &websockets {
+            ws.make_disappear();
+        }
+
         let document = self.Document();
         NodeCast::from_ref(document.r()).teardown();
 
@@ -669,6 +683,12 @@ impl<'a> WindowHelpers for &'a Window {
         *self.browsing_context.borrow_mut() = None;
     }
 
+    fn register_websocket(self, ws: Arc<WebSocketShared>) {
+        let mut websockets = self.websockets.borrow_mut();
+        websockets.retain(|websocket| !websocket.is_closed());
+        websockets.push(ws);
+    }
+
     /// Reflows the page unconditionally. This method will wait for the layout thread to complete
     /// (but see the `TODO` below). If there is no window size yet, the page is presumed invisible
     /// and no reflow is performed.
@@ -1092,6 +1112,7 @@ impl Window {
             window_size: Cell::new(window_size),
             pending_reflow_count: Cell::new(0),
             current_state: Cell::new(WindowState::Alive),
+            websockets: DOMRefCell::new(vec!()),
 
             devtools_marker_sender: RefCell::new(None),
             devtools_markers: RefCell::new(HashSet::new()),
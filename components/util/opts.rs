@@ -132,6 +132,13 @@ pub struct Opts {
     /// An optional string allowing the user agent to be set for testing.
     pub user_agent: Option<String>,
 
+    /// The `host:port` of an HTTP proxy that WebSocket connections are tunneled through.
+    pub proxy: Option<String>,
+
+    /// How many WebSocket connections to the same host and port may be in the CONNECTING state
+    /// at once. RFC 6455 asks for one.
+    pub websocket_connections_per_host: usize,
+
     /// Dumps the flow tree after a layout.
     pub dump_flow_tree: bool,
 
@@ -251,6 +258,8 @@ pub fn default_opts() -> Opts {
         webdriver_port: None,
         initial_window_size: Size2D::typed(800, 600),
         user_agent: None,
+        proxy: None,
+        websocket_connections_per_host: 1,
         dump_flow_tree: false,
         dump_display_list: false,
         dump_display_list_json: false,
@@ -292,6 +301,9 @@ pub fn from_cmdline_args(args: &[String]) {
         getopts::optflagopt("", "webdriver", "Start remote WebDriver server on port", "7000"),
         getopts::optopt("", "resolution", "Set window resolution.", "800x600"),
         getopts::optopt("u", "user-agent", "Set custom user agent string", "NCSA Mosaic/1.0 (X11;SunOS 4.1.4 sun4m)"),
+        getopts::optopt("", "proxy", "Tunnel WebSocket connections through an HTTP proxy", "localhost:3128"),
+        getopts::optopt("", "websocket-connections-per-host",
+                        "Number of WebSocket connections to a host that may be established at once", "1"),
         getopts::optopt("Z", "debug",
                         "A comma-separated string of debug options. Pass help to show available options.", ""),
         getopts::optflag("h", "help", "Print this message"),
@@ -388,6 +400,11 @@ pub fn from_cmdline_args(args: &[String]) {
         port.parse().unwrap()
     });
 
+    let websocket_connections_per_host = match opt_match.opt_str("websocket-connections-per-host") {
+        Some(connections_str) => connections_str.parse().unwrap(),
+        None => 1,
+    };
+
     let initial_window_size = match opt_match.opt_str("resolution") {
         Some(res_string) => {
             let res: Vec<u32> = res_string.split('x').map(|r| r.parse().unwrap()).collect();
@@ -422,6 +439,8 @@ pub fn from_cmdline_args(args: &[String]) {
         webdriver_port: webdriver_port,
         initial_window_size: initial_window_size,
         user_agent: opt_match.opt_str("u"),
+        proxy: opt_match.opt_str("proxy"),
+        websocket_connections_per_host: websocket_connections_per_host,
         show_debug_borders: debug_options.contains(&"show-compositor-borders"),
         show_debug_fragment_borders: debug_options.contains(&"show-fragment-borders"),
         show_debug_parallel_paint: debug_options.contains(&"show-parallel-paint"),
@@ -102,3 +102,15 @@ fn test_sort_order() {
     assert!(CookieStorage::cookie_comparator(&a_prime, &a) == Ordering::Greater);
     assert!(CookieStorage::cookie_comparator(&a, &a) == Ordering::Equal);
 }
+
+#[test]
+fn test_secure_cookies_for_websocket_urls() {
+    let url = &Url::parse("https://example.com/").unwrap();
+    let cookie = cookie_rs::Cookie::parse("baz=bar; Secure").unwrap();
+    let cookie = Cookie::new_wrapped(cookie, url, CookieSource::HTTP).unwrap();
+
+    let ws_url = &Url::parse("ws://example.com/socket").unwrap();
+    assert!(!cookie.appropriate_for_url(ws_url, CookieSource::HTTP));
+    let wss_url = &Url::parse("wss://example.com/socket").unwrap();
+    assert!(cookie.appropriate_for_url(wss_url, CookieSource::HTTP));
+}
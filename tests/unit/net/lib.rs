@@ -4,14 +4,20 @@
 
 #![cfg_attr(test, feature(box_raw))]
 
+extern crate hyper;
+extern crate ipc_channel;
 extern crate net;
 extern crate net_traits;
+extern crate openssl;
+extern crate profile_traits;
 extern crate url;
 extern crate util;
 extern crate time;
+extern crate websocket;
 
 #[cfg(test)] mod cookie;
 #[cfg(test)] mod data_loader;
 #[cfg(test)] mod mime_classifier;
 #[cfg(test)] mod resource_task;
 #[cfg(test)] mod hsts;
+#[cfg(test)] mod websocket_loader;
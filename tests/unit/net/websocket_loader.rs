@@ -0,0 +1,1650 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use hyper::header::{Headers, Host};
+use ipc_channel::ipc;
+use net::resource_task::new_resource_task;
+use net::websocket_loader::{FrameWriter, MESSAGE_TOO_BIG, MemoryUsage, NO_STATUS_RECEIVED};
+use net::websocket_loader::{ConnectionError, append_fragment, connect_tcp, connect_through_proxy, init, resolve};
+use net::websocket_loader::establish_a_websocket_connection;
+use net::websocket_loader::{Backoff, ClosedFlag, Disconnect, Transport, parse_close_payload, run_connection};
+use net::websocket_loader::{DeflateParams, Deflater, Inflater, negotiate_deflate, socket_address};
+use net::websocket_loader::{ConnectionAbort, PendingConnections, write_frame};
+use net::websocket_loader::{WRITE_CHUNK_SIZE, write_frame_in_chunks};
+use net::websocket_loader::{FrameError, certificate_name_matches, read_frame};
+use net::websocket_loader::{WRITE_BATCH_LIMIT, WriteBatch, is_offered_protocol, upgrade_headers_are_valid};
+use net_traits::{ControlMsg, CookieSource};
+use net_traits::{MessageData, WebSocketCommunicate, WebSocketConnectData, WebSocketDomAction};
+use net_traits::WebSocketNetworkEvent;
+use openssl::ssl::{SslContext, SslMethod, SslStream};
+use openssl::x509::X509FileType;
+use profile_traits::mem::ProfilerChan;
+use std::borrow::ToOwned;
+use std::cmp::max;
+use std::io::{self, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread;
+use std::time::Duration;
+use time;
+use url::Url;
+use websocket::Message;
+use websocket::dataframe::Opcode;
+use websocket::header::{WebSocketAccept, WebSocketKey};
+use websocket::header::extensions::{Extension, Parameter};
+use websocket::ws::util::url::parse_url;
+
+#[test]
+fn test_parse_close_payload_with_code() {
+    let payload = [0x0b, 0xb9, b'b', b'y', b'e'];
+    assert_eq!(parse_close_payload(&payload), Ok((3001, "bye".to_owned())));
+}
+
+#[test]
+fn test_parse_empty_close_payload() {
+    assert_eq!(parse_close_payload(&[]), Ok((NO_STATUS_RECEIVED, "".to_owned())));
+}
+
+#[test]
+fn test_parse_malformed_close_payload() {
+    assert_eq!(parse_close_payload(&[0x03]), Err(1002));
+    assert_eq!(parse_close_payload(&[0x03, 0xe8, 0xff]), Err(1007));
+}
+
+#[test]
+fn test_parse_close_payload_with_illegal_code() {
+    // 1006 is reserved for reporting abnormal closure locally.
+    assert_eq!(parse_close_payload(&[0x03, 0xee]), Err(1002));
+    assert_eq!(parse_close_payload(&[0x13, 0x88]), Err(1002));
+}
+
+#[test]
+fn test_parse_close_payload_with_registered_code() {
+    // 1012 to 1014 are registered after RFC 6455; 1015 is reserved.
+    assert_eq!(parse_close_payload(&[0x03, 0xf4]), Ok((1012, "".to_owned())));
+    assert_eq!(parse_close_payload(&[0x03, 0xf5]), Ok((1013, "".to_owned())));
+    assert_eq!(parse_close_payload(&[0x03, 0xf6]), Ok((1014, "".to_owned())));
+    assert_eq!(parse_close_payload(&[0x03, 0xf7]), Err(1002));
+}
+
+#[test]
+fn test_append_fragment_within_limit() {
+    let mut message = vec![1, 2];
+    assert_eq!(append_fragment(&mut message, &[3, 4], 4), Ok(()));
+    assert_eq!(message, vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn test_append_fragment_exceeding_limit() {
+    // Each fragment fits on its own, but the reassembled message doesn't.
+    let mut message = vec![0; 3];
+    assert_eq!(append_fragment(&mut message, &[0; 3], 4), Err(MESSAGE_TOO_BIG));
+    assert_eq!(message.len(), 3);
+}
+
+#[test]
+fn test_memory_reports() {
+    let usage = MemoryUsage::new();
+    usage.send_queue.fetch_add(4 * 1024 * 1024, Ordering::SeqCst);
+    usage.reassembly_buffer.store(1024, Ordering::SeqCst);
+
+    let reports = usage.reports(&Url::parse("ws://example.com/chat").unwrap());
+    assert_eq!(reports.len(), 2);
+    assert_eq!(reports[0].path.connect("/"), "websocket/url(ws://example.com/chat)/send-queue");
+    assert_eq!(reports[0].size, 4 * 1024 * 1024);
+    assert_eq!(reports[1].path.connect("/"), "websocket/url(ws://example.com/chat)/reassembly-buffer");
+    assert_eq!(reports[1].size, 1024);
+}
+
+/// Reads the head of an HTTP request or response from `stream`.
+fn read_head(stream: &mut TcpStream) -> String {
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        if stream.read(&mut byte).unwrap() == 0 {
+            break;
+        }
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+/// Reads exactly `length` bytes from `stream`.
+fn read_bytes(stream: &mut TcpStream, length: usize) -> Vec<u8> {
+    let mut bytes = vec![];
+    stream.take(length as u64).read_to_end(&mut bytes).unwrap();
+    assert_eq!(bytes.len(), length);
+    bytes
+}
+
+/// Starts a proxy that answers a single CONNECT request with `response`,
+/// then sends `tunneled` through the tunnel. Returns the proxy's address.
+fn start_proxy(response: &'static str, tunneled: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let request = read_head(&mut stream);
+        assert!(request.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+        stream.write_all(response.as_bytes()).unwrap();
+        stream.write_all(tunneled.as_bytes()).unwrap();
+    });
+    address
+}
+
+#[test]
+fn test_connect_through_proxy() {
+    let proxy = start_proxy("HTTP/1.1 200 Connection established\r\n\r\n", "hello");
+    let abort = ConnectionAbort::new();
+    let mut stream = connect_through_proxy(&proxy, "example.com:443", Duration::from_secs(5), &abort).unwrap();
+    let mut tunneled = String::new();
+    stream.read_to_string(&mut tunneled).unwrap();
+    assert_eq!(tunneled, "hello");
+}
+
+#[test]
+fn test_proxy_authentication_fails_the_connection() {
+    let proxy = start_proxy("HTTP/1.1 407 Proxy Authentication Required\r\n\r\n", "");
+    let abort = ConnectionAbort::new();
+    assert!(connect_through_proxy(&proxy, "example.com:443", Duration::from_secs(5), &abort).is_err());
+}
+
+/// Asserts that the handshake with a server that accepts the connection on
+/// `scheme` and never replies fails once the handshake timeout elapses.
+fn assert_handshake_times_out(scheme: &str) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (_stream, _) = listener.accept().unwrap();
+        // Keep the connection open without ever replying.
+        thread::sleep_ms(10000);
+    });
+    let url = Url::parse(&format!("{}://{}/", scheme, address)).unwrap();
+    let url = parse_url(&url).unwrap();
+    let start_ns = time::precise_time_ns();
+    let result = establish_a_websocket_connection(url, None, vec![], None, None, Duration::from_millis(200),
+                                                  &ConnectionAbort::new());
+    assert!(result.is_err());
+    assert!(time::precise_time_ns() - start_ns < 5_000_000_000);
+}
+
+#[test]
+fn test_stalled_handshake_times_out() {
+    assert_handshake_times_out("ws");
+}
+
+#[test]
+fn test_stalled_tls_handshake_times_out() {
+    assert_handshake_times_out("wss");
+}
+
+#[test]
+fn test_ipv6_host() {
+    let host = Host { hostname: "[::1]".to_owned(), port: Some(9001) };
+    assert_eq!(socket_address(&host, false), ("::1", 9001));
+    let mut headers = Headers::new();
+    headers.set(host);
+    assert_eq!(headers.to_string(), "Host: [::1]:9001\r\n");
+}
+
+#[test]
+fn test_default_socket_address_port() {
+    let host = Host { hostname: "example.com".to_owned(), port: None };
+    assert_eq!(socket_address(&host, false), ("example.com", 80));
+    assert_eq!(socket_address(&host, true), ("example.com", 443));
+}
+
+#[test]
+fn test_write_masked_frame() {
+    let mut frame = vec![];
+    write_frame(&mut frame, 0x1, false, b"Hello", [0x37, 0xfa, 0x21, 0x3d]).unwrap();
+    // The masked "Hello" example of RFC 6455, section 5.7.
+    assert_eq!(frame, [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]);
+}
+
+#[test]
+fn test_write_empty_frame() {
+    let mut frame = vec![];
+    write_frame(&mut frame, 0x1, false, b"", [0x37, 0xfa, 0x21, 0x3d]).unwrap();
+    assert_eq!(frame, [0x81, 0x80, 0x37, 0xfa, 0x21, 0x3d]);
+}
+
+#[test]
+fn test_write_frame_with_extended_length() {
+    let mut frame = vec![];
+    write_frame(&mut frame, 0x2, false, &[0; 256], [0; 4]).unwrap();
+    assert_eq!(frame[..4].to_vec(), vec![0x82, 0xfe, 0x01, 0x00]);
+    assert_eq!(frame.len(), 4 + 4 + 256);
+
+    let mut frame = vec![];
+    write_frame(&mut frame, 0x2, false, &[0; 65536], [0; 4]).unwrap();
+    assert_eq!(frame[..10].to_vec(), vec![0x82, 0xff, 0, 0, 0, 0, 0, 0x01, 0, 0]);
+}
+
+#[test]
+fn test_consecutive_frames_use_different_masking_keys() {
+    let mut frame_writer = FrameWriter::new(None).unwrap();
+    let mut first = vec![];
+    frame_writer.write_message(&mut first, &Message::Text("hello".to_owned())).unwrap();
+    let mut second = vec![];
+    frame_writer.write_message(&mut second, &Message::Text("hello".to_owned())).unwrap();
+    assert!(first[2..6] != second[2..6]);
+}
+
+/// Completes the opening handshake of a single connection to `listener`,
+/// accepting `extensions` if given, and then runs `serve` on it with the
+/// head of the request.
+fn serve_handshake<F>(listener: TcpListener, extensions: Option<&'static str>, serve: F)
+                      where F: FnOnce(TcpStream, String) {
+    let (mut stream, _) = listener.accept().unwrap();
+    let request = read_head(&mut stream);
+    let key = request.lines().find(|line| line.starts_with("Sec-WebSocket-Key: ")).unwrap();
+    let key = WebSocketKey::from_str(key["Sec-WebSocket-Key: ".len()..].trim()).unwrap();
+    let accept = WebSocketAccept::new(&key);
+    write!(stream, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                    Sec-WebSocket-Accept: {}\r\n", accept.serialize()).unwrap();
+    if let Some(extensions) = extensions {
+        write!(stream, "Sec-WebSocket-Extensions: {}\r\n", extensions).unwrap();
+    }
+    stream.write_all(b"\r\n").unwrap();
+    serve(stream, request);
+}
+
+/// Starts a WebSocket server that completes the opening handshake of a
+/// single connection, accepting `extensions` if given, and then runs `serve`
+/// on it with the head of the request. Returns the URL of the server.
+fn start_server<F>(extensions: Option<&'static str>, serve: F) -> Url
+                   where F: FnOnce(TcpStream, String) + Send + 'static {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || serve_handshake(listener, extensions, serve));
+    Url::parse(&format!("ws://127.0.0.1:{}/", port)).unwrap()
+}
+
+/// The user agent of the connections opened by `connect`.
+const USER_AGENT: &'static str = "Servo WebSocket tests";
+
+/// Opens a connection to `url` in the WebSocket loader, returning the
+/// sender of its actions and the receiver of its events.
+fn connect(url: Url) -> (Sender<WebSocketDomAction>, Receiver<WebSocketNetworkEvent>) {
+    connect_with_protocols(url, vec![])
+}
+
+/// Like `connect`, offering `protocols`.
+fn connect_with_protocols(url: Url, protocols: Vec<String>)
+                          -> (Sender<WebSocketDomAction>, Receiver<WebSocketNetworkEvent>) {
+    let pending_connections = Arc::new(PendingConnections::new(1));
+    connect_with_pending_connections(url, None, protocols, pending_connections)
+}
+
+/// Like `connect_with_protocols`, on behalf of `origin` if given, counting
+/// the connection while it is established in `pending_connections`.
+fn connect_with_pending_connections(url: Url, origin: Option<String>, protocols: Vec<String>,
+                                    pending_connections: Arc<PendingConnections>)
+                                    -> (Sender<WebSocketDomAction>, Receiver<WebSocketNetworkEvent>) {
+    let (event_sender, event_receiver) = channel();
+    let (action_sender, action_receiver) = channel();
+    let (profiler_sender, _) = ipc::channel().unwrap();
+    let (resource_task, _) = channel();
+    let connect = WebSocketCommunicate {
+        event_sender: event_sender,
+        action_receiver: action_receiver,
+    };
+    let connect_data = WebSocketConnectData {
+        resource_url: url,
+        origin: origin,
+        protocols: protocols,
+        mem_profiler_chan: ProfilerChan(profiler_sender),
+    };
+    init(connect, connect_data, None, Some(USER_AGENT.to_owned()), resource_task, pending_connections, None);
+    (action_sender, event_receiver)
+}
+
+/// Takes the next event of a connection, which must report it as
+/// established, and returns the headers of the handshake response.
+fn expect_established(events: &Receiver<WebSocketNetworkEvent>) -> Headers {
+    match events.recv().unwrap() {
+        WebSocketNetworkEvent::ConnectionEstablished(headers) => headers,
+        _ => panic!("the connection should be established"),
+    }
+}
+
+/// Takes the next event of a connection, which must report it as failed.
+fn expect_fail(events: &Receiver<WebSocketNetworkEvent>) {
+    match events.recv().unwrap() {
+        WebSocketNetworkEvent::Fail => {}
+        _ => panic!("the connection should fail"),
+    }
+}
+
+/// Takes the next event of a connection, which must report it as closed
+/// with `code`, and returns the reason.
+fn expect_close(events: &Receiver<WebSocketNetworkEvent>, code: u16) -> String {
+    match events.recv().unwrap() {
+        WebSocketNetworkEvent::Close(received_code, reason) => {
+            assert_eq!(received_code, code);
+            reason
+        }
+        _ => panic!("the connection should close"),
+    }
+}
+
+/// Takes the next event of a connection, which must be a text message, and
+/// returns its text.
+fn expect_text(events: &Receiver<WebSocketNetworkEvent>) -> String {
+    match events.recv().unwrap() {
+        WebSocketNetworkEvent::MessageReceived(MessageData::Text(text)) => text,
+        _ => panic!("a text message should be received"),
+    }
+}
+
+/// Takes the next event of a connection, which must be a binary message,
+/// and returns its data.
+fn expect_binary(events: &Receiver<WebSocketNetworkEvent>) -> Vec<u8> {
+    match events.recv().unwrap() {
+        WebSocketNetworkEvent::MessageReceived(MessageData::Binary(data)) => data,
+        _ => panic!("a binary message should be received"),
+    }
+}
+
+/// Takes the next event of a connection, which must report a message as
+/// sent, and returns the amount of data it held.
+fn expect_sent(events: &Receiver<WebSocketNetworkEvent>) -> u32 {
+    match events.recv().unwrap() {
+        WebSocketNetworkEvent::MessageSent(amount) => amount,
+        _ => panic!("a message should be sent"),
+    }
+}
+
+#[test]
+fn test_handshake_sends_the_given_origin() {
+    // The ASCII serialization of the origin of a worker's script URL, such as
+    // https://example.com:8443/js/worker.js, which keeps its port.
+    let (request_sender, request_receiver) = channel();
+    let url = start_server(None, move |_, request| request_sender.send(request).unwrap());
+    let pending_connections = Arc::new(PendingConnections::new(1));
+    let _connection = connect_with_pending_connections(url, Some("https://example.com:8443".to_owned()), vec![],
+                                                       pending_connections);
+    let request = request_receiver.recv().unwrap();
+    assert!(request.lines().any(|line| line.trim() == "Origin: https://example.com:8443"));
+}
+
+#[test]
+fn test_message_before_close_is_reported_first() {
+    let url = start_server(None, |mut stream, _| {
+        stream.write_all(&[0x81, 0x03, b'b', b'y', b'e']).unwrap();
+        stream.write_all(&[0x88, 0x02, 0x03, 0xe8]).unwrap();
+        let _ = stream.read(&mut [0; 64]);
+    });
+    let (_actions, events) = connect(url);
+    expect_established(&events);
+    assert_eq!(expect_text(&events), "bye");
+    expect_close(&events, 1000);
+}
+
+/// Reads what is sent on a channel, as if it came from the network. The
+/// stream ends when the sender is dropped.
+struct ChannelReader {
+    receiver: Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffer.is_empty() {
+            match self.receiver.recv() {
+                Ok(data) => self.buffer = data,
+                Err(_) => return Ok(0),
+            }
+        }
+        let length = (&self.buffer[..]).read(buf).unwrap();
+        self.buffer = self.buffer[length..].to_vec();
+        Ok(length)
+    }
+}
+
+/// Sends everything written to it on a channel, one message per write.
+struct ChannelWriter(Sender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.0.send(buf.to_vec()).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "closed")));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A channel can't be taken down from the reading side; tests end the stream
+/// by dropping its sender instead.
+struct NoDisconnect;
+
+impl Disconnect for NoDisconnect {
+    fn disconnect(&self) {}
+}
+
+/// A transport over channels, which the test plays the server on.
+struct MockTransport {
+    reader: ChannelReader,
+    writer: ChannelWriter,
+}
+
+impl Transport for MockTransport {
+    type Reader = ChannelReader;
+    type Writer = ChannelWriter;
+    type Disconnector = NoDisconnect;
+
+    fn split(self) -> io::Result<(ChannelReader, ChannelWriter, NoDisconnect)> {
+        Ok((self.reader, self.writer, NoDisconnect))
+    }
+}
+
+/// Runs a connection over a mock transport, returning the sender of its
+/// actions, the receiver of its events, the sender of what the server
+/// writes and the receiver of what the client writes.
+fn connect_mock() -> (Sender<WebSocketDomAction>, Receiver<WebSocketNetworkEvent>,
+                      Sender<Vec<u8>>, Receiver<Vec<u8>>) {
+    connect_mock_with_actions(vec![])
+}
+
+/// Like `connect_mock`, with `actions` already queued when the handshake
+/// completes.
+fn connect_mock_with_actions(actions: Vec<WebSocketDomAction>)
+                             -> (Sender<WebSocketDomAction>, Receiver<WebSocketNetworkEvent>,
+                                 Sender<Vec<u8>>, Receiver<Vec<u8>>) {
+    let (server_sender, client_receiver) = channel();
+    let (client_sender, server_receiver) = channel();
+    let transport = MockTransport {
+        reader: ChannelReader {
+            receiver: client_receiver,
+            buffer: vec![],
+        },
+        writer: ChannelWriter(client_sender),
+    };
+    let (event_sender, event_receiver) = channel();
+    let (action_sender, action_receiver) = channel();
+    for action in actions {
+        action_sender.send(action).unwrap();
+    }
+    let (profiler_sender, _) = ipc::channel().unwrap();
+    let connect = WebSocketCommunicate {
+        event_sender: event_sender,
+        action_receiver: action_receiver,
+    };
+    let url = Url::parse("ws://example.com/").unwrap();
+    thread::spawn(move || {
+        run_connection(transport, connect, Headers::new(), None, url, ProfilerChan(profiler_sender), None,
+                       "mock".to_owned());
+    });
+    (action_sender, event_receiver, server_sender, server_receiver)
+}
+
+#[test]
+fn test_mock_transport_open_message_close() {
+    let (actions, events, server_sender, server_receiver) = connect_mock();
+    expect_established(&events);
+
+    actions.send(WebSocketDomAction::SendMessage(MessageData::Text("hello".to_owned()))).unwrap();
+    let frame = server_receiver.recv().unwrap();
+    assert_eq!(frame[..2].to_vec(), vec![0x81, 0x85]);
+    let payload = frame[6..].iter().zip(frame[2..6].iter().cycle()).map(|(byte, key)| byte ^ key);
+    assert_eq!(payload.collect::<Vec<u8>>(), b"hello".to_vec());
+    assert_eq!(expect_sent(&events), 5);
+
+    server_sender.send(vec![0x81, 0x02, b'h', b'i']).unwrap();
+    assert_eq!(expect_text(&events), "hi");
+
+    server_sender.send(vec![0x88, 0x02, 0x03, 0xe8]).unwrap();
+    expect_close(&events, 1000);
+}
+
+#[test]
+fn test_abort_after_open_sends_a_close_frame() {
+    let (actions, events, _server_sender, server_receiver) = connect_mock();
+    expect_established(&events);
+    actions.send(WebSocketDomAction::Abort).unwrap();
+    let frame = server_receiver.recv().unwrap();
+    // A close frame without a status code, written before disconnecting.
+    assert_eq!(frame[..2].to_vec(), vec![0x88, 0x80]);
+    // The writer stops, although the WebSocket is still around.
+    assert!(server_receiver.recv().is_err());
+}
+
+#[test]
+fn test_interleaved_text_and_binary_messages() {
+    let (_actions, events, server_sender, _server_receiver) = connect_mock();
+    expect_established(&events);
+    server_sender.send(vec![0x81, 0x01, b'a']).unwrap();
+    // A binary message fragmented around a ping.
+    server_sender.send(vec![0x02, 0x01, 0x01]).unwrap();
+    server_sender.send(vec![0x89, 0x00]).unwrap();
+    server_sender.send(vec![0x80, 0x01, 0x02]).unwrap();
+    server_sender.send(vec![0x81, 0x01, b'b']).unwrap();
+    assert_eq!(expect_text(&events), "a");
+    assert_eq!(expect_binary(&events), vec![1u8, 2]);
+    assert_eq!(expect_text(&events), "b");
+}
+
+#[test]
+fn test_close_frame_carries_code_and_reason() {
+    let (actions, events, _server_sender, server_receiver) = connect_mock();
+    expect_established(&events);
+    actions.send(WebSocketDomAction::Close(Some((1000, "bye".to_owned())))).unwrap();
+    let frame = server_receiver.recv().unwrap();
+    assert_eq!(frame[..2].to_vec(), vec![0x88, 0x85]);
+    let payload = frame[6..].iter().zip(frame[2..6].iter().cycle()).map(|(byte, key)| byte ^ key);
+    assert_eq!(payload.collect::<Vec<u8>>(), vec![0x03, 0xe8, b'b', b'y', b'e']);
+}
+
+#[test]
+fn test_close_reports_the_code_of_the_server() {
+    let (actions, events, server_sender, server_receiver) = connect_mock();
+    expect_established(&events);
+    actions.send(WebSocketDomAction::Close(Some((3001, "bye".to_owned())))).unwrap();
+    let frame = server_receiver.recv().unwrap();
+    assert_eq!(frame[..2].to_vec(), vec![0x88, 0x85]);
+    let payload = frame[6..].iter().zip(frame[2..6].iter().cycle()).map(|(byte, key)| byte ^ key);
+    assert_eq!(payload.collect::<Vec<u8>>(), vec![0x0b, 0xb9, b'b', b'y', b'e']);
+
+    server_sender.send(vec![0x88, 0x02, 0x0b, 0xba]).unwrap();
+    assert_eq!(expect_close(&events, 3002), "");
+}
+
+#[test]
+fn test_close_from_the_server_is_answered() {
+    let (_actions, events, server_sender, server_receiver) = connect_mock();
+    expect_established(&events);
+    server_sender.send(vec![0x88, 0x02, 0x03, 0xe9]).unwrap();
+    let frame = server_receiver.recv().unwrap();
+    assert_eq!(frame[..2].to_vec(), vec![0x88, 0x82]);
+    let payload = frame[6..].iter().zip(frame[2..6].iter().cycle()).map(|(byte, key)| byte ^ key);
+    assert_eq!(payload.collect::<Vec<u8>>(), vec![0x03, 0xe9]);
+    expect_close(&events, 1001);
+}
+
+#[test]
+fn test_writer_stops_once_both_close_frames_are_exchanged() {
+    let (actions, events, server_sender, server_receiver) = connect_mock();
+    expect_established(&events);
+    actions.send(WebSocketDomAction::Close(None)).unwrap();
+    let frame = server_receiver.recv().unwrap();
+    assert_eq!(frame[..2].to_vec(), vec![0x88, 0x80]);
+    server_sender.send(vec![0x88, 0x00]).unwrap();
+    expect_close(&events, NO_STATUS_RECEIVED);
+    // Nothing is written after the closing handshake, and the writer is
+    // gone while the WebSocket still holds on to its actions.
+    assert!(server_receiver.recv().is_err());
+    drop(actions);
+}
+
+#[test]
+fn test_close_without_code_from_the_server_is_answered_without_code() {
+    let (_actions, events, server_sender, server_receiver) = connect_mock();
+    expect_established(&events);
+    server_sender.send(vec![0x88, 0x00]).unwrap();
+    let frame = server_receiver.recv().unwrap();
+    assert_eq!(frame[..2].to_vec(), vec![0x88, 0x80]);
+    assert_eq!(frame.len(), 6);
+    expect_close(&events, NO_STATUS_RECEIVED);
+}
+
+/// An unmasked frame from the server, with `first_byte` holding the FIN
+/// bit and the opcode.
+fn server_frame(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![first_byte];
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else if payload.len() <= 0xffff {
+        frame.push(126);
+        frame.push((payload.len() >> 8) as u8);
+        frame.push(payload.len() as u8);
+    } else {
+        frame.push(127);
+        for shift in (0..8).rev() {
+            frame.push((payload.len() as u64 >> (shift * 8)) as u8);
+        }
+    }
+    frame.extend(payload.iter().cloned());
+    frame
+}
+
+#[test]
+fn test_large_fragmented_binary_message() {
+    const LENGTH: usize = 1024 * 1024;
+    let message: Vec<u8> = (0..LENGTH).map(|i| (i % 251) as u8).collect();
+    let (_actions, events, server_sender, _server_receiver) = connect_mock();
+    expect_established(&events);
+    // Fragments of every length encoding, split at odd offsets.
+    let boundaries = [0, 1, 126, 65535, 65536 + 65535, 700001, LENGTH];
+    for i in 0..boundaries.len() - 1 {
+        let opcode = if i == 0 { 0x02 } else { 0x00 };
+        let fin = if i == boundaries.len() - 2 { 0x80 } else { 0x00 };
+        server_sender.send(server_frame(fin | opcode, &message[boundaries[i]..boundaries[i + 1]])).unwrap();
+    }
+    let data = expect_binary(&events);
+    assert_eq!(data.len(), LENGTH);
+    assert!(data == message);
+}
+
+#[test]
+fn test_large_receive_is_handled_off_the_script_task() {
+    const LENGTH: usize = 1024 * 1024;
+    let (_actions, events, server_sender, server_receiver) = connect_mock();
+    expect_established(&events);
+    server_sender.send(server_frame(0x02, &vec![1u8; LENGTH / 2])).unwrap();
+    // The connection answers a ping in the middle of the message by itself,
+    // without queueing anything for the script task.
+    server_sender.send(server_frame(0x89, b"ping")).unwrap();
+    let frame = server_receiver.recv().unwrap();
+    assert_eq!(frame[..2].to_vec(), vec![0x8a, 0x84]);
+    server_sender.send(server_frame(0x80, &vec![1u8; LENGTH / 2])).unwrap();
+    // The script task only gets the message once it is complete, as a
+    // single task.
+    assert_eq!(expect_binary(&events).len(), LENGTH);
+}
+
+#[test]
+fn test_close_during_handshake_that_succeeds() {
+    let (_actions, events, _server_sender, server_receiver) = connect_mock_with_actions(vec![
+        WebSocketDomAction::Abort,
+    ]);
+    expect_fail(&events);
+    assert!(events.recv().is_err());
+    // A masked close frame with no status code.
+    let frame = server_receiver.recv().unwrap();
+    assert_eq!(frame[..2].to_vec(), vec![0x88, 0x80]);
+    assert_eq!(frame.len(), 6);
+}
+
+#[test]
+fn test_close_while_connecting_never_opens() {
+    let (go_sender, go_receiver) = channel::<()>();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    // Hold back the handshake response until close() has been called.
+    thread::spawn(move || {
+        let _ = go_receiver.recv();
+        serve_handshake(listener, None, |mut stream, _| {
+            let _ = stream.read(&mut [0; 64]);
+        });
+    });
+    let (actions, events) = connect(Url::parse(&format!("ws://127.0.0.1:{}/", port)).unwrap());
+    actions.send(WebSocketDomAction::Abort).unwrap();
+    go_sender.send(()).unwrap();
+    // No ConnectionEstablished, so no open event is ever queued.
+    expect_fail(&events);
+    assert!(events.recv().is_err());
+}
+
+#[test]
+fn test_close_during_handshake_that_fails() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    drop(listener);
+    let (actions, events) = connect(Url::parse(&format!("ws://127.0.0.1:{}/", port)).unwrap());
+    // The connection may already have failed.
+    let _ = actions.send(WebSocketDomAction::Abort);
+    expect_fail(&events);
+    assert!(events.recv().is_err());
+}
+
+/// Asserts that closing a connection whose handshake never completes fails
+/// it right away, rather than once the handshake times out.
+fn assert_close_while_connecting_is_prompt(url: Url, pending_connections: Arc<PendingConnections>) {
+    let (actions, events) = connect_with_pending_connections(url, None, vec![], pending_connections);
+    thread::sleep_ms(100);
+    let start_ns = time::precise_time_ns();
+    actions.send(WebSocketDomAction::Abort).unwrap();
+    expect_fail(&events);
+    assert!(time::precise_time_ns() - start_ns < 1_000_000_000);
+}
+
+#[test]
+fn test_close_during_stalled_handshake_is_prompt() {
+    // The server accepts the connection, and never answers the handshake.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("ws://{}/", listener.local_addr().unwrap())).unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _ = stream.read_to_end(&mut vec![]);
+    });
+    assert_close_while_connecting_is_prompt(url, Arc::new(PendingConnections::new(1)));
+}
+
+#[test]
+fn test_close_while_waiting_for_another_connection_is_prompt() {
+    // Another connection to the host is still being established.
+    let url = Url::parse("ws://127.0.0.1:9/").unwrap();
+    let pending_connections = Arc::new(PendingConnections::new(1));
+    let _other = pending_connections.wait_for_turn("127.0.0.1", 9, &ConnectionAbort::new()).unwrap();
+    assert_close_while_connecting_is_prompt(url, pending_connections.clone());
+}
+
+#[test]
+fn test_refused_handshake_fails_the_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        read_head(&mut stream);
+        stream.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n").unwrap();
+    });
+    let (_actions, events) = connect(Url::parse(&format!("ws://127.0.0.1:{}/", port)).unwrap());
+    expect_fail(&events);
+    assert!(events.recv().is_err());
+}
+
+fn deflate_response(params: Vec<(&str, Option<&str>)>) -> Vec<Extension> {
+    let params = params.into_iter().map(|(name, value)| {
+        Parameter { name: name.to_owned(), value: value.map(|value| value.to_owned()) }
+    }).collect();
+    vec![Extension { name: "permessage-deflate".to_owned(), params: params }]
+}
+
+#[test]
+fn test_negotiate_deflate() {
+    assert_eq!(negotiate_deflate(&[]), Ok(None));
+    assert_eq!(negotiate_deflate(&deflate_response(vec![])), Ok(Some(DeflateParams::default())));
+    assert_eq!(negotiate_deflate(&deflate_response(vec![("server_max_window_bits", Some("10"))])),
+               Ok(Some(DeflateParams::default())));
+}
+
+#[test]
+fn test_negotiate_deflate_no_context_takeover() {
+    let params = negotiate_deflate(&deflate_response(vec![("client_no_context_takeover", None)]));
+    assert_eq!(params, Ok(Some(DeflateParams {
+        client_no_context_takeover: true,
+        server_no_context_takeover: false,
+    })));
+    let params = negotiate_deflate(&deflate_response(vec![("server_no_context_takeover", None),
+                                                          ("client_no_context_takeover", None)]));
+    assert_eq!(params, Ok(Some(DeflateParams {
+        client_no_context_takeover: true,
+        server_no_context_takeover: true,
+    })));
+    assert!(negotiate_deflate(&deflate_response(vec![("client_no_context_takeover", Some("1"))])).is_err());
+    assert!(negotiate_deflate(&deflate_response(vec![("server_no_context_takeover", None),
+                                                     ("server_no_context_takeover", None)])).is_err());
+}
+
+#[test]
+fn test_negotiate_deflate_with_invalid_parameters() {
+    assert!(negotiate_deflate(&deflate_response(vec![("server_max_window_bits", Some("16"))])).is_err());
+    assert!(negotiate_deflate(&deflate_response(vec![("server_max_window_bits", None)])).is_err());
+    // We never offer to limit our own window.
+    assert!(negotiate_deflate(&deflate_response(vec![("client_max_window_bits", Some("10"))])).is_err());
+    assert!(negotiate_deflate(&deflate_response(vec![("unknown", None)])).is_err());
+    let mut twice = deflate_response(vec![]);
+    twice.extend(deflate_response(vec![]));
+    assert!(negotiate_deflate(&twice).is_err());
+}
+
+#[test]
+fn test_inflate() {
+    // The compressed "Hello" example of RFC 7692, section 7.2.3.1.
+    let mut inflater = Inflater::new(false);
+    assert_eq!(inflater.inflate(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], 1024), Ok(b"Hello".to_vec()));
+    // The second message of section 7.2.3.2 refers to the first one.
+    assert_eq!(inflater.inflate(&[0xf2, 0x00, 0x11, 0x00, 0x00], 1024), Ok(b"Hello".to_vec()));
+}
+
+#[test]
+fn test_inflate_too_big() {
+    let data = vec![b'a'; 4096];
+    let compressed = Deflater::new(false).deflate(&data);
+    assert_eq!(Inflater::new(false).inflate(&compressed, 4095), Err(MESSAGE_TOO_BIG));
+    assert_eq!(Inflater::new(false).inflate(&compressed, 4096), Ok(data));
+}
+
+#[test]
+fn test_inflate_invalid_data() {
+    assert!(Inflater::new(false).inflate(&[0xff, 0xff, 0xff], 1024).is_err());
+}
+
+#[test]
+fn test_deflate_round_trip() {
+    let mut deflater = Deflater::new(false);
+    let mut inflater = Inflater::new(false);
+    for message in &["Hello", "Hello", "", "Hello, world"] {
+        let compressed = deflater.deflate(message.as_bytes());
+        assert!(!compressed.ends_with(&[0x00, 0x00, 0xff, 0xff]));
+        assert_eq!(inflater.inflate(&compressed, 1024), Ok(message.as_bytes().to_vec()));
+    }
+}
+
+#[test]
+fn test_compressed_echo() {
+    let url = start_server(Some("permessage-deflate"), |mut stream, _| {
+        // Echo the first frame back unmasked, keeping its compression.
+        let header = read_bytes(&mut stream, 2);
+        assert_eq!(header[0], 0xc1);
+        let length = (header[1] & 0x7f) as usize;
+        let masking_key = read_bytes(&mut stream, 4);
+        let mut payload = read_bytes(&mut stream, length);
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= masking_key[i % 4];
+        }
+        stream.write_all(&[0xc1, length as u8]).unwrap();
+        stream.write_all(&payload).unwrap();
+        let _ = stream.read(&mut [0; 64]);
+    });
+    let (actions, events) = connect(url);
+    expect_established(&events);
+    actions.send(WebSocketDomAction::SendMessage(MessageData::Text("Hello, hello".to_owned()))).unwrap();
+    loop {
+        match events.recv().unwrap() {
+            WebSocketNetworkEvent::MessageSent(_) => {}
+            WebSocketNetworkEvent::MessageReceived(MessageData::Text(ref text)) if text == "Hello, hello" => break,
+            _ => panic!("the echo should decompress to the message sent"),
+        }
+    }
+}
+
+#[test]
+fn test_deflate_without_context_takeover() {
+    let mut deflater = Deflater::new(true);
+    let first = deflater.deflate(b"Hello");
+    let second = deflater.deflate(b"Hello");
+    // Without the context of the first message, the second one can't refer
+    // back to it.
+    assert_eq!(first, second);
+    for compressed in &[first, second] {
+        assert_eq!(Inflater::new(true).inflate(compressed, 1024), Ok(b"Hello".to_vec()));
+    }
+}
+
+#[test]
+fn test_inflate_without_context_takeover() {
+    let mut inflater = Inflater::new(true);
+    for _ in 0..3 {
+        assert_eq!(inflater.inflate(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], 1024), Ok(b"Hello".to_vec()));
+    }
+}
+
+#[test]
+fn test_negotiate_unsolicited_extension() {
+    let extension = Extension { name: "x-webkit-deflate-frame".to_owned(), params: vec![] };
+    assert!(negotiate_deflate(&[extension]).is_err());
+}
+
+#[test]
+fn test_unsolicited_extension_fails_the_connection() {
+    let url = start_server(Some("x-webkit-deflate-frame"), |mut stream, _| {
+        let _ = stream.read(&mut [0; 64]);
+    });
+    let (_actions, events) = connect(url);
+    expect_fail(&events);
+}
+
+#[test]
+fn test_wrong_accept_key_fails_the_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _ = read_head(&mut stream);
+        // The accept key of the example handshake of RFC 6455, section 1.3,
+        // which doesn't match the random key of the request.
+        stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                           Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n").unwrap();
+        let _ = stream.read(&mut [0; 64]);
+    });
+    let (_actions, events) = connect(Url::parse(&format!("ws://127.0.0.1:{}/", port)).unwrap());
+    expect_fail(&events);
+}
+
+#[test]
+fn test_send_after_server_went_away_fails_the_connection() {
+    let url = start_server(None, |stream, _| drop(stream));
+    let (actions, events) = connect(url);
+    expect_established(&events);
+    actions.send(WebSocketDomAction::SendMessage(MessageData::Text("hello".to_owned()))).unwrap();
+    loop {
+        match events.recv().unwrap() {
+            WebSocketNetworkEvent::MessageSent(_) => {}
+            WebSocketNetworkEvent::Fail => break,
+            _ => panic!("the connection should fail"),
+        }
+    }
+}
+
+#[test]
+fn test_pending_connections_to_a_host_are_serialized() {
+    let pending_connections = Arc::new(PendingConnections::new(1));
+    let abort = ConnectionAbort::new();
+    let first = pending_connections.wait_for_turn("example.com", 80, &abort).unwrap();
+
+    let second_started = Arc::new(AtomicBool::new(false));
+    let thread_pending_connections = pending_connections.clone();
+    let thread_second_started = second_started.clone();
+    let second = thread::spawn(move || {
+        let _second = thread_pending_connections.wait_for_turn("example.com", 80, &ConnectionAbort::new()).unwrap();
+        thread_second_started.store(true, Ordering::SeqCst);
+    });
+
+    // Other hosts, and other ports of the same host, don't wait.
+    drop(pending_connections.wait_for_turn("example.org", 80, &abort).unwrap());
+    drop(pending_connections.wait_for_turn("example.com", 8080, &abort).unwrap());
+
+    thread::sleep_ms(100);
+    assert!(!second_started.load(Ordering::SeqCst));
+    drop(first);
+    second.join().unwrap();
+    assert!(second_started.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_aborted_connection_stops_waiting_for_its_turn() {
+    let pending_connections = Arc::new(PendingConnections::new(1));
+    let _first = pending_connections.wait_for_turn("example.com", 80, &ConnectionAbort::new()).unwrap();
+
+    let abort = Arc::new(ConnectionAbort::new());
+    let thread_pending_connections = pending_connections.clone();
+    let thread_abort = abort.clone();
+    let second = thread::spawn(move || {
+        thread_pending_connections.wait_for_turn("example.com", 80, &thread_abort).is_none()
+    });
+    thread::sleep_ms(100);
+    abort.abort();
+    pending_connections.wake_waiting();
+    assert!(second.join().unwrap());
+}
+
+#[test]
+fn test_handshake_sends_user_agent() {
+    let (request_sender, request_receiver) = channel();
+    let url = start_server(None, move |mut stream, request| {
+        request_sender.send(request).unwrap();
+        let _ = stream.read(&mut [0; 64]);
+    });
+    let (_actions, _events) = connect(url);
+    let request = request_receiver.recv().unwrap();
+    assert!(request.lines().any(|line| line.trim() == format!("User-Agent: {}", USER_AGENT)));
+}
+
+#[test]
+fn test_handshake_offers_protocols_in_order() {
+    let (request_sender, request_receiver) = channel();
+    let url = start_server(None, move |mut stream, request| {
+        request_sender.send(request).unwrap();
+        let _ = stream.read(&mut [0; 64]);
+    });
+    let (_actions, _events) = connect_with_protocols(url, vec!["chat".to_owned(), "superchat".to_owned()]);
+    let request = request_receiver.recv().unwrap();
+    assert!(request.lines().any(|line| line.trim() == "Sec-WebSocket-Protocol: chat, superchat"));
+}
+
+#[test]
+fn test_refused_connection_is_told_apart() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    match connect_tcp("127.0.0.1", port) {
+        Err(ConnectionError::ConnectionRefused(_)) => {}
+        result => panic!("Expected the connection to be refused, got {:?}", result.map(|_| ())),
+    }
+    let url = Url::parse(&format!("ws://127.0.0.1:{}/", port)).unwrap();
+    let (_actions, events) = connect(url);
+    expect_fail(&events);
+}
+
+#[test]
+fn test_unresolved_host_is_told_apart() {
+    match connect_tcp("nonexistent.invalid", 80) {
+        Err(ConnectionError::HostNotFound(_)) => {}
+        result => panic!("Expected the host not to be found, got {:?}", result.map(|_| ())),
+    }
+    let (_actions, events) = connect(Url::parse("ws://nonexistent.invalid/").unwrap());
+    expect_fail(&events);
+}
+
+#[test]
+fn test_refused_connection_is_not_retried() {
+    // Nothing listens on the port at first; a server starts after the first
+    // attempt has been refused, in time for a retry.
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    thread::spawn(move || {
+        thread::sleep_ms(100);
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        serve_handshake(listener, None, |mut stream, _| {
+            let _ = stream.read(&mut [0; 64]);
+        });
+    });
+    let (_actions, events) = connect(Url::parse(&format!("ws://127.0.0.1:{}/", port)).unwrap());
+    expect_fail(&events);
+}
+
+#[test]
+fn test_resolve() {
+    let addresses = resolve("127.0.0.1", 8080, 1000).unwrap();
+    assert_eq!(addresses, vec!["127.0.0.1:8080".parse::<SocketAddr>().unwrap()]);
+}
+
+#[test]
+fn test_send_large_blob() {
+    const LENGTH: usize = 4 * 1024 * 1024;
+    let url = start_server(None, |mut stream, _| {
+        let header = read_bytes(&mut stream, 10);
+        assert_eq!(header[0], 0x82);
+        assert_eq!(header[1], 0x80 | 127);
+        assert_eq!(header[2..].to_vec(), vec![0, 0, 0, 0, 0, 0x40, 0, 0]);
+        let masking_key = read_bytes(&mut stream, 4);
+        let payload = read_bytes(&mut stream, LENGTH);
+        assert!(payload.iter().enumerate().all(|(i, byte)| byte ^ masking_key[i % 4] == 7));
+        let _ = stream.read(&mut [0; 64]);
+    });
+    let (actions, events) = connect(url);
+    expect_established(&events);
+    actions.send(WebSocketDomAction::SendBlob(Arc::new(vec![7; LENGTH]))).unwrap();
+    assert_eq!(expect_sent(&events) as usize, LENGTH);
+}
+
+#[test]
+fn test_sending_large_blobs_does_not_wait_for_the_network() {
+    const LENGTH: usize = 4 * 1024 * 1024;
+    const BLOBS: usize = 8;
+    let url = start_server(None, |mut stream, _| {
+        // Don't read anything until the blobs have all been handed over.
+        thread::sleep_ms(500);
+        for _ in 0..BLOBS {
+            read_bytes(&mut stream, 14 + LENGTH);
+        }
+        let _ = stream.read(&mut [0; 64]);
+    });
+    let (actions, events) = connect(url);
+    expect_established(&events);
+    // Sending shares the blob's data with the connection, so the script task
+    // is done with a send long before the server reads any of it.
+    let data = Arc::new(vec![7u8; LENGTH]);
+    let start_ns = time::precise_time_ns();
+    for _ in 0..BLOBS {
+        actions.send(WebSocketDomAction::SendBlob(data.clone())).unwrap();
+    }
+    assert!(time::precise_time_ns() - start_ns < 250_000_000);
+    for _ in 0..BLOBS {
+        assert_eq!(expect_sent(&events) as usize, LENGTH);
+    }
+}
+
+#[test]
+fn test_large_blob_is_written_in_bounded_chunks() {
+    const LENGTH: usize = 4 * 1024 * 1024;
+    let (actions, events, _server_sender, server_receiver) = connect_mock();
+    expect_established(&events);
+    actions.send(WebSocketDomAction::SendBlob(Arc::new(vec![7; LENGTH]))).unwrap();
+    assert_eq!(expect_sent(&events) as usize, LENGTH);
+    // The mock writer hands over one write at a time: the 14 byte header,
+    // then the payload, never more than a chunk of it at once.
+    let mut total = 0;
+    while total < 14 + LENGTH {
+        let write = server_receiver.recv().unwrap();
+        assert!(write.len() <= WRITE_CHUNK_SIZE);
+        total += write.len();
+    }
+    assert_eq!(total, 14 + LENGTH);
+}
+
+#[test]
+fn test_small_blob_is_sent_as_one_frame() {
+    let (actions, events, _server_sender, server_receiver) = connect_mock();
+    expect_established(&events);
+    actions.send(WebSocketDomAction::SendBlob(Arc::new(vec![1u8, 2, 3]))).unwrap();
+    let frame = server_receiver.recv().unwrap();
+    assert_eq!(frame[..2].to_vec(), vec![0x82, 0x83]);
+    let payload: Vec<u8> = frame[6..].iter().enumerate().map(|(i, byte)| byte ^ frame[2 + i % 4]).collect();
+    assert_eq!(payload, vec![1u8, 2, 3]);
+}
+
+#[test]
+fn test_messages_sent_before_close_are_written_first() {
+    let (result_sender, result_receiver) = channel();
+    let url = start_server(None, move |mut stream, _| {
+        let mut opcodes = vec![];
+        for _ in 0..4 {
+            let header = read_bytes(&mut stream, 2);
+            let length = (header[1] & 0x7f) as usize;
+            read_bytes(&mut stream, 4 + length);
+            opcodes.push(header[0]);
+        }
+        result_sender.send(opcodes).unwrap();
+    });
+    let (actions, events) = connect(url);
+    expect_established(&events);
+    for text in &["one", "two", "three"] {
+        actions.send(WebSocketDomAction::SendMessage(MessageData::Text(text.to_string()))).unwrap();
+    }
+    actions.send(WebSocketDomAction::Close(Some((1000, "".to_owned())))).unwrap();
+    assert_eq!(result_receiver.recv().unwrap(), vec![0x81, 0x81, 0x81, 0x88]);
+}
+
+#[test]
+fn test_queued_messages_reach_a_slow_reader_in_order() {
+    const MESSAGES: usize = 128;
+    const LENGTH: usize = 60000;
+    let (result_sender, result_receiver) = channel();
+    let url = start_server(None, move |mut stream, _| {
+        // Let the socket buffers fill up, so that writing blocks.
+        thread::sleep_ms(500);
+        let mut indices = vec![];
+        for _ in 0..MESSAGES {
+            let header = read_bytes(&mut stream, 4);
+            assert_eq!(header, vec![0x82, 0x80 | 126, (LENGTH >> 8) as u8, LENGTH as u8]);
+            let masking_key = read_bytes(&mut stream, 4);
+            let payload = read_bytes(&mut stream, LENGTH);
+            let index = payload[0] ^ masking_key[0];
+            assert!(payload.iter().enumerate().all(|(i, byte)| byte ^ masking_key[i % 4] == index));
+            indices.push(index as usize);
+        }
+        result_sender.send(indices).unwrap();
+    });
+    let (actions, events) = connect(url);
+    expect_established(&events);
+    for index in 0..MESSAGES {
+        actions.send(WebSocketDomAction::SendMessage(MessageData::Binary(vec![index as u8; LENGTH]))).unwrap();
+    }
+    assert_eq!(result_receiver.recv().unwrap(), (0..MESSAGES).collect::<Vec<_>>());
+    for _ in 0..MESSAGES {
+        assert_eq!(expect_sent(&events) as usize, LENGTH);
+    }
+}
+
+#[test]
+fn test_upgrade_headers_are_valid() {
+    let mut headers = Headers::new();
+    headers.set_raw("Upgrade", vec![b"WebSocket".to_vec()]);
+    headers.set_raw("Connection", vec![b"keep-alive, upgrade".to_vec()]);
+    assert!(upgrade_headers_are_valid(&headers));
+    headers.remove_raw("Connection");
+    assert!(!upgrade_headers_are_valid(&headers));
+}
+
+/// Starts a server that answers the opening handshake of a single
+/// connection with a 101 response that has a valid accept key, and otherwise
+/// only the given `headers`, each followed by CRLF.
+fn start_server_with_response_headers(headers: &'static str) -> Url {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let request = read_head(&mut stream);
+        let key = request.lines().find(|line| line.starts_with("Sec-WebSocket-Key: ")).unwrap();
+        let key = WebSocketKey::from_str(key["Sec-WebSocket-Key: ".len()..].trim()).unwrap();
+        write!(stream, "HTTP/1.1 101 Switching Protocols\r\n{}Sec-WebSocket-Accept: {}\r\n\r\n",
+               headers, WebSocketAccept::new(&key).serialize()).unwrap();
+        let _ = stream.read(&mut [0; 64]);
+    });
+    Url::parse(&format!("ws://127.0.0.1:{}/", port)).unwrap()
+}
+
+#[test]
+fn test_missing_upgrade_header_fails_the_connection() {
+    let (_actions, events) = connect(start_server_with_response_headers("Connection: Upgrade\r\n"));
+    expect_fail(&events);
+}
+
+#[test]
+fn test_missing_connection_header_fails_the_connection() {
+    let (_actions, events) = connect(start_server_with_response_headers("Upgrade: websocket\r\n"));
+    expect_fail(&events);
+}
+
+#[test]
+fn test_handshake_redirect_fails_the_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _ = read_head(&mut stream);
+        stream.write_all(b"HTTP/1.1 302 Found\r\nLocation: ws://example.com/\r\nContent-Length: 0\r\n\r\n")
+              .unwrap();
+    });
+    let (_actions, events) = connect(Url::parse(&format!("ws://127.0.0.1:{}/", port)).unwrap());
+    expect_fail(&events);
+}
+
+#[test]
+fn test_cookies_set_for_websocket_url() {
+    let resource_task = new_resource_task(None, None);
+    let ws_url = Url::parse("ws://example.com/socket").unwrap();
+    resource_task.send(ControlMsg::SetCookiesForUrl(ws_url, "foo=bar; Path=/".to_owned(),
+                                                    CookieSource::HTTP)).unwrap();
+    let (tx, rx) = channel();
+    let url = Url::parse("http://example.com/").unwrap();
+    resource_task.send(ControlMsg::GetCookiesForUrl(url, tx, CookieSource::HTTP)).unwrap();
+    assert_eq!(rx.recv().unwrap(), Some("foo=bar".to_owned()));
+    resource_task.send(ControlMsg::Exit).unwrap();
+}
+
+#[test]
+fn test_connections_to_other_ports_of_a_host_are_concurrent() {
+    // The first server doesn't answer the handshake until the second one has
+    // received its request, which it never would if the second connection
+    // waited for the first one.
+    let second_requested = Arc::new(AtomicBool::new(false));
+    let (result_sender, result_receiver) = channel();
+    let first_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let first_port = first_listener.local_addr().unwrap().port();
+    let first_second_requested = second_requested.clone();
+    thread::spawn(move || {
+        let (mut stream, _) = first_listener.accept().unwrap();
+        let _ = read_head(&mut stream);
+        for _ in 0..200 {
+            if first_second_requested.load(Ordering::SeqCst) {
+                break;
+            }
+            thread::sleep_ms(10);
+        }
+        result_sender.send(first_second_requested.load(Ordering::SeqCst)).unwrap();
+    });
+    let second_url = start_server(None, move |mut stream, _| {
+        second_requested.store(true, Ordering::SeqCst);
+        let _ = stream.read(&mut [0; 64]);
+    });
+
+    let pending_connections = Arc::new(PendingConnections::new(1));
+    let first_url = Url::parse(&format!("ws://127.0.0.1:{}/", first_port)).unwrap();
+    let _first = connect_with_pending_connections(first_url, None, vec![], pending_connections.clone());
+    let _second = connect_with_pending_connections(second_url, None, vec![], pending_connections);
+    assert!(result_receiver.recv().unwrap());
+}
+
+#[test]
+fn test_send_empty_text_message() {
+    let (result_sender, result_receiver) = channel();
+    let url = start_server(None, move |mut stream, _| {
+        result_sender.send(read_bytes(&mut stream, 2)).unwrap();
+        let _ = stream.read(&mut [0; 64]);
+    });
+    let (actions, events) = connect(url);
+    expect_established(&events);
+    actions.send(WebSocketDomAction::SendMessage(MessageData::Text("".to_owned()))).unwrap();
+    // A final text frame with a masked, empty payload.
+    assert_eq!(result_receiver.recv().unwrap(), vec![0x81, 0x80]);
+    assert_eq!(expect_sent(&events), 0);
+}
+
+#[test]
+fn test_frame_writer_counts_frames_sent() {
+    let mut writer = FrameWriter::new(None).unwrap();
+    let mut frames = vec![];
+    for _ in 0..5 {
+        writer.write_message(&mut frames, &Message::Text("Hello".to_owned())).unwrap();
+    }
+    let counters = writer.counters();
+    assert_eq!(counters.frames_sent.load(Ordering::SeqCst), 5);
+    assert_eq!(counters.bytes_sent.load(Ordering::SeqCst), 25);
+    assert_eq!(counters.frames_received.load(Ordering::SeqCst), 0);
+}
+
+/// A writer that counts how many times it is written to.
+struct CountingWriter {
+    writes: usize,
+    data: Vec<u8>,
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes += 1;
+        self.data.extend(buf.iter().cloned());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_write_batch_coalesces_writes() {
+    let mut frame_writer = FrameWriter::new(None).unwrap();
+    let mut unbatched = CountingWriter { writes: 0, data: vec![] };
+    for _ in 0..100 {
+        frame_writer.write_message(&mut unbatched, &Message::Text("hi".to_owned())).unwrap();
+    }
+    assert_eq!(unbatched.writes, 100);
+
+    let mut batch = WriteBatch::new();
+    let mut batched = CountingWriter { writes: 0, data: vec![] };
+    for _ in 0..100 {
+        batch.push(&mut frame_writer, &Message::Text("hi".to_owned()), Some(2));
+    }
+    assert!(!batch.is_full());
+    assert_eq!(batch.flush(&mut batched).unwrap(), vec![2u32; 100]);
+    assert_eq!(batched.writes, 1);
+    // Each message is still its own frame.
+    assert_eq!(batched.data.len(), 100 * 8);
+    assert_eq!(batched.data.len(), unbatched.data.len());
+}
+
+#[test]
+fn test_write_batch_is_full_past_the_limit() {
+    let mut frame_writer = FrameWriter::new(None).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.push(&mut frame_writer, &Message::Binary(vec![0; WRITE_BATCH_LIMIT]), Some(WRITE_BATCH_LIMIT as u32));
+    assert!(batch.is_full());
+    let mut writer = CountingWriter { writes: 0, data: vec![] };
+    batch.flush(&mut writer).unwrap();
+    assert!(!batch.is_full());
+    assert!(batch.flush(&mut writer).unwrap().is_empty());
+    assert_eq!(writer.writes, 1);
+}
+
+#[test]
+fn test_selected_protocol_must_be_one_offered_protocol() {
+    let offered = vec!["chat".to_owned(), "superchat".to_owned()];
+    assert!(is_offered_protocol(&["superchat".to_owned()], &offered));
+    assert!(!is_offered_protocol(&["Chat".to_owned()], &offered));
+    assert!(!is_offered_protocol(&["chat".to_owned(), "superchat".to_owned()], &offered));
+    assert!(!is_offered_protocol(&[], &offered));
+}
+
+#[test]
+fn test_case_mismatched_protocol_fails_the_connection() {
+    let url = start_server_with_response_headers("Upgrade: websocket\r\nConnection: Upgrade\r\n\
+                                                  Sec-WebSocket-Protocol: chat\r\n");
+    let (_actions, events) = connect_with_protocols(url, vec!["Chat".to_owned()]);
+    expect_fail(&events);
+}
+
+#[test]
+fn test_no_selected_protocol_opens_the_connection() {
+    let url = start_server_with_response_headers("Upgrade: websocket\r\nConnection: Upgrade\r\n");
+    let (_actions, events) = connect_with_protocols(url, vec!["chat".to_owned()]);
+    let headers = expect_established(&events);
+    assert!(headers.get_raw("Sec-WebSocket-Protocol").is_none());
+}
+
+#[test]
+fn test_selected_protocol_reaches_the_established_connection() {
+    let url = start_server_with_response_headers("Upgrade: websocket\r\nConnection: Upgrade\r\n\
+                                                  Sec-WebSocket-Protocol: superchat\r\n");
+    let (_actions, events) = connect_with_protocols(url, vec!["chat".to_owned(), "superchat".to_owned()]);
+    let headers = expect_established(&events);
+    assert_eq!(headers.get_raw("Sec-WebSocket-Protocol").unwrap(), &[b"superchat".to_vec()][..]);
+}
+
+#[test]
+fn test_agreed_extensions_reach_the_established_connection() {
+    let url = start_server(Some("permessage-deflate"), |mut stream, _| {
+        let _ = stream.read(&mut [0; 64]);
+    });
+    let (_actions, events) = connect(url);
+    let headers = expect_established(&events);
+    assert_eq!(headers.get_raw("Sec-WebSocket-Extensions").unwrap(), &[b"permessage-deflate".to_vec()][..]);
+}
+
+#[test]
+fn test_no_agreed_extensions_reach_the_established_connection() {
+    let url = start_server(None, |mut stream, _| {
+        let _ = stream.read(&mut [0; 64]);
+    });
+    let (_actions, events) = connect(url);
+    let headers = expect_established(&events);
+    assert!(headers.get_raw("Sec-WebSocket-Extensions").is_none());
+}
+
+#[test]
+fn test_server_dying_mid_connection_fails_the_connection() {
+    let (kill_sender, kill_receiver) = channel::<()>();
+    let url = start_server(None, move |stream, _| {
+        let _ = kill_receiver.recv();
+        // Go away without a close frame.
+        drop(stream);
+    });
+    let (_actions, events) = connect(url);
+    expect_established(&events);
+    drop(kill_sender);
+    // Reported to script as closed with 1006, not cleanly.
+    expect_fail(&events);
+}
+
+#[test]
+fn test_dropping_the_websocket_closes_the_connection() {
+    let (result_sender, result_receiver) = channel();
+    let url = start_server(None, move |mut stream, _| {
+        result_sender.send(read_bytes(&mut stream, 2)).unwrap();
+    });
+    let (actions, events) = connect(url);
+    expect_established(&events);
+    drop(actions);
+    // A close frame without a status code.
+    assert_eq!(result_receiver.recv().unwrap(), vec![0x88, 0x80]);
+}
+
+#[test]
+fn test_closed_flag_wait_times_out() {
+    let closed = ClosedFlag::new();
+    assert!(!closed.wait_ms(50));
+    assert!(!closed.is_set());
+}
+
+#[test]
+fn test_closed_flag_wait_returns_once_set() {
+    let closed = Arc::new(ClosedFlag::new());
+    let setter = closed.clone();
+    thread::spawn(move || {
+        thread::sleep_ms(10);
+        setter.set();
+    });
+    let start_ns = time::precise_time_ns();
+    // The keepalive thread waits a whole interval; it must still stop as
+    // soon as the connection closes.
+    assert!(closed.wait_ms(60000));
+    assert!(time::precise_time_ns() - start_ns < 5_000_000_000);
+}
+
+#[test]
+fn test_read_frame() {
+    let frame = read_frame(&mut Cursor::new(vec![0x81, 0x05, b'H', b'e', b'l', b'l', b'o']), 1024).unwrap();
+    assert!(frame.finished);
+    assert_eq!(frame.opcode, Opcode::Text);
+    assert_eq!(frame.data, b"Hello".to_vec());
+}
+
+#[test]
+fn test_read_frame_rejects_huge_length_from_header() {
+    // Claims an 8 EiB payload, none of which follows.
+    let header = vec![0x82, 127, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+    match read_frame(&mut Cursor::new(header), 1024) {
+        Err(FrameError::Invalid(code)) => assert_eq!(code, MESSAGE_TOO_BIG),
+        _ => panic!("the frame should be too big"),
+    }
+}
+
+#[test]
+fn test_read_frame_rejects_long_control_frame() {
+    match read_frame(&mut Cursor::new(vec![0x89, 126, 0x00, 0x7e]), 1024) {
+        Err(FrameError::Invalid(code)) => assert_eq!(code, 1002),
+        _ => panic!("the ping should be too long"),
+    }
+}
+
+#[test]
+fn test_read_frame_rejects_fragmented_control_frame() {
+    // A ping without the FIN bit.
+    match read_frame(&mut Cursor::new(vec![0x09, 0x00]), 1024) {
+        Err(FrameError::Invalid(code)) => assert_eq!(code, 1002),
+        _ => panic!("the ping should not be fragmented"),
+    }
+}
+
+#[test]
+fn test_huge_frame_fails_the_connection_with_1009() {
+    let (result_sender, result_receiver) = channel();
+    let url = start_server(None, move |mut stream, _| {
+        stream.write_all(&[0x82, 127, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00]).unwrap();
+        let header = read_bytes(&mut stream, 2);
+        let masking_key = read_bytes(&mut stream, 4);
+        let code = read_bytes(&mut stream, 2);
+        result_sender.send((header, vec![code[0] ^ masking_key[0], code[1] ^ masking_key[1]])).unwrap();
+    });
+    let (_actions, events) = connect(url);
+    expect_established(&events);
+    expect_fail(&events);
+    let (header, code) = result_receiver.recv().unwrap();
+    assert_eq!(header[0], 0x88);
+    assert_eq!(code, vec![0x03, 0xf1]);
+}
+
+#[test]
+fn test_upgrade_required_fails_the_connection() {
+    let (request_sender, request_receiver) = channel();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        request_sender.send(read_head(&mut stream)).unwrap();
+        stream.write_all(b"HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: 8, 7\r\n\r\n").unwrap();
+    });
+    let (_actions, events) = connect(Url::parse(&format!("ws://127.0.0.1:{}/", port)).unwrap());
+    expect_fail(&events);
+    let request = request_receiver.recv().unwrap();
+    assert!(request.lines().any(|line| line.trim() == "Sec-WebSocket-Version: 13"));
+}
+
+#[test]
+fn test_certificate_name_matches() {
+    assert!(certificate_name_matches("example.com", "example.com"));
+    assert!(certificate_name_matches("Example.COM", "example.com"));
+    assert!(certificate_name_matches("*.example.com", "www.example.com"));
+    assert!(!certificate_name_matches("example.com", "example.org"));
+    assert!(!certificate_name_matches("*.example.com", "example.com"));
+    assert!(!certificate_name_matches("*.example.com", "a.b.example.com"));
+    assert!(!certificate_name_matches("*.example.com", ".example.com"));
+}
+
+#[test]
+fn test_failed_tls_handshake_fails_the_connection() {
+    // A server that doesn't speak TLS at all.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+    });
+    let (_actions, events) = connect(Url::parse(&format!("wss://127.0.0.1:{}/", port)).unwrap());
+    expect_fail(&events);
+}
+
+#[test]
+fn test_self_signed_certificate_fails_the_connection() {
+    let mut context = SslContext::new(SslMethod::Sslv23).unwrap();
+    context.set_certificate_file(&"websocket_certs/self_signed.pem", X509FileType::PEM).unwrap();
+    context.set_private_key_file(&"websocket_certs/self_signed.key", X509FileType::PEM).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        // The client gives up on the certificate, so this fails too.
+        let _ = SslStream::new_server(&context, stream);
+    });
+    let (_actions, events) = connect(Url::parse(&format!("wss://127.0.0.1:{}/", port)).unwrap());
+    // A failed connection is reported to script as closed with 1006.
+    expect_fail(&events);
+    assert!(events.recv().is_err());
+}
+
+#[test]
+fn test_second_close_sends_no_second_close_frame() {
+    let (result_sender, result_receiver) = channel();
+    let url = start_server(None, move |mut stream, _| {
+        let first = read_bytes(&mut stream, 8);
+        stream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        let more = match stream.read(&mut [0; 64]) {
+            Ok(length) => length,
+            Err(_) => 0,
+        };
+        result_sender.send((first[0], more)).unwrap();
+    });
+    let (actions, events) = connect(url);
+    expect_established(&events);
+    actions.send(WebSocketDomAction::Close(Some((1000, "".to_owned())))).unwrap();
+    actions.send(WebSocketDomAction::Close(Some((1000, "".to_owned())))).unwrap();
+    let (opcode, more) = result_receiver.recv().unwrap();
+    assert_eq!(opcode, 0x88);
+    assert_eq!(more, 0);
+}
+
+#[test]
+fn test_abort_interrupts_a_blocked_write() {
+    // The server never reads, so writing a large message blocks once the
+    // socket buffers are full.
+    let url = start_server(None, |_stream, _| thread::sleep_ms(5000));
+    let (actions, events) = connect(url);
+    expect_established(&events);
+    actions.send(WebSocketDomAction::SendMessage(MessageData::Binary(vec![0; 32 * 1024 * 1024]))).unwrap();
+    actions.send(WebSocketDomAction::Abort).unwrap();
+    expect_fail(&events);
+}
+
+#[test]
+fn test_backoff_ceiling_doubles_up_to_the_maximum() {
+    let mut backoff = Backoff::new(100, 1000).unwrap();
+    let mut ceilings = vec![];
+    for _ in 0..6 {
+        ceilings.push(backoff.ceiling());
+        backoff.next_delay();
+    }
+    assert_eq!(ceilings, vec![100u32, 200, 400, 800, 1000, 1000]);
+    for _ in 0..100 {
+        backoff.next_delay();
+    }
+    assert_eq!(backoff.ceiling(), 1000);
+    backoff.reset();
+    assert_eq!(backoff.ceiling(), 100);
+}
+
+#[test]
+fn test_backoff_jitter_range() {
+    let mut backoff = Backoff::new(1000, 1000).unwrap();
+    let delays: Vec<u32> = (0..100).map(|_| backoff.next_delay()).collect();
+    assert!(delays.iter().all(|&delay| delay >= 500 && delay <= 1000));
+    assert!(delays.iter().any(|&delay| delay != delays[0]));
+}
+
+#[test]
+fn test_write_frame_in_chunks_matches_write_frame() {
+    let masking_key = [0x37, 0xfa, 0x21, 0x3d];
+    for &length in &[0usize, 5, 126, 70000, 3 * WRITE_CHUNK_SIZE + 3] {
+        let payload: Vec<u8> = (0..length).map(|i| i as u8).collect();
+        let mut expected = vec![];
+        write_frame(&mut expected, 0x2, false, &payload, masking_key).unwrap();
+        let mut frame = vec![];
+        write_frame_in_chunks(&mut frame, 0x2, &payload, masking_key).unwrap();
+        assert!(frame == expected);
+    }
+}
+
+/// Keeps track of the largest write it gets, without keeping the data.
+struct LargestWriteWriter {
+    largest: usize,
+    total: usize,
+}
+
+impl Write for LargestWriteWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.largest = max(self.largest, buf.len());
+        self.total += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_large_frame_is_written_in_chunks() {
+    const LENGTH: usize = 4 * 1024 * 1024;
+    let mut writer = LargestWriteWriter {
+        largest: 0,
+        total: 0,
+    };
+    write_frame_in_chunks(&mut writer, 0x2, &vec![7; LENGTH], [1, 2, 3, 4]).unwrap();
+    assert_eq!(writer.total, 14 + LENGTH);
+    assert!(writer.largest <= WRITE_CHUNK_SIZE);
+}
@@ -4,6 +4,9 @@
 
 extern crate script;
 extern crate msg;
+extern crate net_traits;
+extern crate url;
 
 #[cfg(all(test, target_pointer_width = "64"))] mod size_of;
 #[cfg(test)] mod textinput;
+#[cfg(test)] mod websocket;
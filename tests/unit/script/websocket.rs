@@ -0,0 +1,253 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use script::dom::bindings::str::USVString;
+use script::dom::websocket::{CloseKind, MAX_BUFFERED_AMOUNT, MAX_BUFFERED_MESSAGES, ascii_serialize_origin};
+use script::dom::websocket::{close_frame_data, is_valid_close_code, send_queue_is_full};
+use script::dom::websocket::{extensions_value, is_port_blocked};
+use script::dom::websocket::{is_valid_close_reason, parse_web_socket_url, protocols_are_valid, send_buffer_is_full};
+use script::dom::websocket::is_insecure_connection_from_secure_document;
+use std::borrow::ToOwned;
+use url::Url;
+
+#[test]
+fn test_blocked_ports() {
+    assert!(is_port_blocked(21));
+    assert!(is_port_blocked(25));
+    assert!(is_port_blocked(110));
+    assert!(is_port_blocked(6667));
+}
+
+#[test]
+fn test_default_ports_are_not_blocked() {
+    assert!(!is_port_blocked(80));
+    assert!(!is_port_blocked(443));
+    assert!(!is_port_blocked(8080));
+}
+
+#[test]
+fn test_origin_omits_path() {
+    let url = Url::parse("http://example.com/page.html").unwrap();
+    assert_eq!(ascii_serialize_origin(&url), Some("http://example.com".to_owned()));
+}
+
+#[test]
+fn test_origin_keeps_non_default_port() {
+    let url = Url::parse("https://example.com:8443/a?b#c").unwrap();
+    assert_eq!(ascii_serialize_origin(&url), Some("https://example.com:8443".to_owned()));
+}
+
+#[test]
+fn test_origin_of_web_socket_url() {
+    let url = Url::parse("ws://example.com:8080/socket").unwrap();
+    assert_eq!(ascii_serialize_origin(&url), Some("ws://example.com:8080".to_owned()));
+    let url = Url::parse("wss://example.com/socket").unwrap();
+    assert_eq!(ascii_serialize_origin(&url), Some("wss://example.com".to_owned()));
+}
+
+#[test]
+fn test_opaque_origin_is_not_serialized() {
+    let url = Url::parse("data:text/html,hello").unwrap();
+    assert_eq!(ascii_serialize_origin(&url), None);
+}
+
+#[test]
+fn test_parse_web_socket_url() {
+    let base = Url::parse("http://example.com/page.html").unwrap();
+    let url = parse_web_socket_url(&base, "ws://example.org/path?query").unwrap();
+    assert_eq!(url.serialize(), "ws://example.org/path?query");
+}
+
+#[test]
+fn test_parse_web_socket_url_with_fragment() {
+    let base = Url::parse("http://example.com/page.html").unwrap();
+    assert!(parse_web_socket_url(&base, "ws://example.com/path#frag").is_err());
+    assert!(parse_web_socket_url(&base, "ws://example.com/path#").is_err());
+}
+
+#[test]
+fn test_parse_web_socket_url_with_other_scheme() {
+    let base = Url::parse("file:///page.html").unwrap();
+    assert!(parse_web_socket_url(&base, "ftp://example.com/").is_err());
+    assert!(parse_web_socket_url(&base, "data:text/plain,hello").is_err());
+    assert!(parse_web_socket_url(&base, "/socket").is_err());
+}
+
+#[test]
+fn test_parse_web_socket_url_upgrades_http_schemes() {
+    let base = Url::parse("file:///page.html").unwrap();
+    let url = parse_web_socket_url(&base, "http://example.com/socket").unwrap();
+    assert_eq!(url.serialize(), "ws://example.com/socket");
+    let url = parse_web_socket_url(&base, "https://example.com:8443/socket").unwrap();
+    assert_eq!(url.serialize(), "wss://example.com:8443/socket");
+}
+
+#[test]
+fn test_parse_relative_web_socket_url() {
+    let base = Url::parse("https://example.com/chat/page.html").unwrap();
+    let url = parse_web_socket_url(&base, "/socket").unwrap();
+    assert_eq!(url.serialize(), "wss://example.com/socket");
+    let url = parse_web_socket_url(&base, "socket").unwrap();
+    assert_eq!(url.serialize(), "wss://example.com/chat/socket");
+}
+
+#[test]
+fn test_web_socket_url_serialization_omits_default_port() {
+    let base = Url::parse("http://example.com/page.html").unwrap();
+    let url = parse_web_socket_url(&base, "ws://example.com/").unwrap();
+    assert_eq!(url.serialize(), "ws://example.com/");
+    let url = parse_web_socket_url(&base, "ws://example.com:80/").unwrap();
+    assert_eq!(url.serialize(), "ws://example.com/");
+    let url = parse_web_socket_url(&base, "wss://example.com:443/").unwrap();
+    assert_eq!(url.serialize(), "wss://example.com/");
+    let url = parse_web_socket_url(&base, "ws://example.com:8080/").unwrap();
+    assert_eq!(url.serialize(), "ws://example.com:8080/");
+    let url = parse_web_socket_url(&base, "http://example.com:443/").unwrap();
+    assert_eq!(url.serialize(), "ws://example.com:443/");
+}
+
+#[test]
+fn test_send_buffer_is_full() {
+    assert!(!send_buffer_is_full(0, 0));
+    assert!(!send_buffer_is_full(0, MAX_BUFFERED_AMOUNT));
+    assert!(!send_buffer_is_full(MAX_BUFFERED_AMOUNT - 10, 10));
+    assert!(send_buffer_is_full(MAX_BUFFERED_AMOUNT - 10, 11));
+    assert!(send_buffer_is_full(MAX_BUFFERED_AMOUNT, 1));
+}
+
+#[test]
+fn test_send_queue_is_full() {
+    assert!(!send_queue_is_full(0));
+    assert!(!send_queue_is_full(MAX_BUFFERED_MESSAGES - 1));
+    assert!(send_queue_is_full(MAX_BUFFERED_MESSAGES));
+}
+
+#[test]
+fn test_send_buffer_is_full_on_overflow() {
+    assert!(send_buffer_is_full(1, !0));
+}
+
+#[test]
+fn test_valid_close_codes() {
+    assert!(is_valid_close_code(1000));
+    assert!(is_valid_close_code(3000));
+    assert!(is_valid_close_code(4999));
+}
+
+#[test]
+fn test_reserved_close_codes_are_invalid() {
+    for &code in &[0u16, 999, 1001, 1002, 1005, 1006, 1011, 1015, 2999, 5000, 65535] {
+        assert!(!is_valid_close_code(code), "{} should be rejected", code);
+    }
+}
+
+#[test]
+fn test_close_frame_data_defaults_to_normal_closure() {
+    assert_eq!(close_frame_data(None, None), (1000, "".to_owned()));
+    assert_eq!(close_frame_data(Some(3000), None), (3000, "".to_owned()));
+    assert_eq!(close_frame_data(Some(4000), Some("bye".to_owned())), (4000, "bye".to_owned()));
+}
+
+#[test]
+fn test_close_frame_data_with_reason_only() {
+    // close(undefined, "bye"): a reason can only go on the wire after a code.
+    assert_eq!(close_frame_data(None, Some("bye".to_owned())), (1000, "bye".to_owned()));
+}
+
+#[test]
+fn test_close_reason_length_is_in_bytes() {
+    // 41 three-byte characters are exactly 123 bytes.
+    let reason: String = (0..41).map(|_| '\u{20ac}').collect();
+    assert_eq!(reason.len(), 123);
+    assert!(is_valid_close_reason(&reason));
+    assert!(!is_valid_close_reason(&format!("{}a", reason)));
+    // Fewer than 123 characters, but more than 123 bytes.
+    let reason: String = (0..42).map(|_| '\u{20ac}').collect();
+    assert!(!is_valid_close_reason(&reason));
+}
+
+#[test]
+fn test_single_protocol_is_validated_as_a_list_of_one() {
+    assert!(protocols_are_valid(&["chat".to_owned()]));
+    assert!(!protocols_are_valid(&["".to_owned()]));
+    assert!(!protocols_are_valid(&["two words".to_owned()]));
+}
+
+#[test]
+fn test_protocol_with_separator_is_invalid() {
+    assert!(!protocols_are_valid(&["chat,superchat".to_owned()]));
+    assert!(!protocols_are_valid(&["chat/1.0".to_owned()]));
+    assert!(!protocols_are_valid(&["(chat)".to_owned()]));
+    assert!(!protocols_are_valid(&["\"chat\"".to_owned()]));
+    assert!(protocols_are_valid(&["chat-1.0_x~'!#$%&*+^`|".to_owned()]));
+}
+
+#[test]
+fn test_protocol_list_validation() {
+    assert!(protocols_are_valid(&[]));
+    assert!(protocols_are_valid(&["chat".to_owned(), "superchat".to_owned()]));
+    assert!(!protocols_are_valid(&["chat".to_owned(), "chat".to_owned()]));
+    assert!(!protocols_are_valid(&["chat".to_owned(), "\u{e9}".to_owned()]));
+}
+
+#[test]
+fn test_close_while_connecting_fires_no_error() {
+    assert!(!CloseKind::Aborted.fires_error(false));
+    assert!(!CloseKind::Aborted.was_clean(false));
+}
+
+#[test]
+fn test_refused_and_aborted_handshakes_report_different_outcomes() {
+    // The server refused the handshake: error, then an unclean close.
+    let refused = CloseKind::of_failure(false);
+    assert_eq!(refused, CloseKind::Failed);
+    assert!(refused.fires_error(false));
+    assert!(!refused.was_clean(false));
+    // close() was called before the handshake completed: only a close.
+    let aborted = CloseKind::of_failure(true);
+    assert_eq!(aborted, CloseKind::Aborted);
+    assert!(!aborted.fires_error(false));
+    assert!(!aborted.was_clean(false));
+}
+
+#[test]
+fn test_network_failure_fires_error() {
+    assert!(CloseKind::Failed.fires_error(false));
+    assert!(!CloseKind::Failed.was_clean(false));
+    assert!(CloseKind::Clean.fires_error(true));
+    assert!(!CloseKind::Clean.was_clean(true));
+    assert!(!CloseKind::Clean.fires_error(false));
+    assert!(CloseKind::Clean.was_clean(false));
+}
+
+#[test]
+fn test_extensions_are_reported_verbatim() {
+    let lines = vec![b"permessage-deflate; server_max_window_bits=10".to_vec()];
+    assert_eq!(extensions_value(&lines), "permessage-deflate; server_max_window_bits=10");
+}
+
+#[test]
+fn test_extension_header_lines_are_concatenated() {
+    let lines = vec![b"permessage-deflate".to_vec(), b"x-webkit-deflate-frame".to_vec()];
+    assert_eq!(extensions_value(&lines), "permessage-deflate, x-webkit-deflate-frame");
+}
+
+#[test]
+fn test_sent_strings_replace_lone_surrogates() {
+    // send() takes a USVString, so "a\ud800b" is sent as "a\u{fffd}b".
+    assert_eq!(USVString::from_utf16(&[0x61, 0xd800, 0x62]).0, "a\u{fffd}b");
+    assert_eq!(USVString::from_utf16(&[0xdc00]).0, "\u{fffd}");
+    // Paired surrogates are kept.
+    assert_eq!(USVString::from_utf16(&[0xd83d, 0xde00]).0, "\u{1f600}");
+}
+
+#[test]
+fn test_ws_from_an_https_document_is_refused() {
+    let secure_document = Url::parse("https://example.com/").unwrap();
+    let document = Url::parse("http://example.com/").unwrap();
+    assert!(is_insecure_connection_from_secure_document(&secure_document, false));
+    assert!(!is_insecure_connection_from_secure_document(&secure_document, true));
+    assert!(!is_insecure_connection_from_secure_document(&document, false));
+    assert!(!is_insecure_connection_from_secure_document(&document, true));
+}